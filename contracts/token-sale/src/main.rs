@@ -8,16 +8,33 @@
 //! 0: The Owner's Lock Script Hash (32 Bytes)
 //! 1: The Cost per token in CKByte Shannons. (u64 LE 8 Bytes)
 //! 2: A unique identifier for the Token Sale Cell. (u32 LE 4 bytes)
+//! 3: Flags which enable optional features. (u8 1 byte)
+//! 4: The field of each flag which is set, in the order of the flag bits.
+//!    0x01: The sale start epoch. (u64 LE 8 bytes)
+//!    0x02: Reserved. (Unsupported)
+//!    0x04: The Merkle root of the whitelisted buyer lock hashes. (32 bytes)
+//!    0x08: The buy-back cost per token in CKByte Shannons. (u64 LE 8 bytes)
+//!    0x10: The price denominator, the number of tokens sold for each cost. (u64 LE 8 bytes)
+//!    0x20: Multiple Token Sale Cells with identical args can be used in one transaction. (No field)
+//!    0x40: The exchange may favor the Token Sale Cell, allowing buyers to overpay. (No field)
 //! 
 //! Constraints
-//! 1. The arguments must be equal or greater than 40 bytes in length. The arguments length will be 44 bytes or more with a unique identifier, but the Script does not check this.
+//! 1. The arguments must be equal or greater than 40 bytes in length. The arguments length will be 44 bytes or more with a unique identifier. Any bytes after the first 44 are read as flags and fields.
 //! 2. If an input Cell's lock hash matches that specified in the args, owner mode is then enabled and the Cell unlocks unconditionally.
-//! 3. The transaction must have exactly one input Cell and one output Cell using the Token Sale Lock Script. These Lock Scripts must have the same arguments.
+//! 3. The transaction must have exactly one input Cell and one output Cell using the Token Sale Lock Script, unless multiple Cells are enabled (constraint 15). These Lock Scripts must have the same arguments.
 //! 4. The Type Script of both the input Token Sale Cell and output Token Sale Cell must match.
 //! 5. The cost of SUDTs in Shannons must be greater than or equal to 1.
 //! 6. The capacity on the output Token Sale Cell must be higher than on the input Token Sale Cell.
 //! 7. The SUDT amount of the output Token Sale Cell must be lower than the input Token Sale Cell.
 //! 8. The capacity difference between the input/output Token Sale Cells must equal the SUDT amount difference between the input/output Token Sale Cells multiplied by the cost.
+//! 9. Any data following the SUDT amount must be identical in the input/output Token Sale Cells.
+//! 10. If flags are present, they must all be supported and be followed by exactly the fields they require.
+//! 11. If a sale start is set, the since of the input Token Sale Cell must be an absolute epoch on or after the start.
+//! 12. If a whitelist is set, the witness lock of the input Token Sale Cell must be the index of an input Cell as a u32 LE followed by a Merkle proof from the lock hash of that Cell to the whitelist root. Cells with the whitelisted lock must receive the tokens sold, or provide the tokens bought back.
+//! 13. If buy-back is enabled, the capacity may instead decrease while the SUDT amount increases. The capacity difference must equal the SUDT amount difference multiplied by the buy-back cost, which must be greater than or equal to 1 and less than or equal to the cost.
+//! 14. If a price denominator is set, the costs are per denominator tokens. The capacity difference multiplied by the denominator must equal the SUDT amount difference multiplied by the cost, and the denominator must be greater than or equal to 1.
+//! 15. If multiple Cells are enabled, the transaction may have several input Cells using the Token Sale Lock Script and the same number of output Cells. Their Type Scripts must all match, constraints 6 to 8, 13, and 14 apply to the totals of the Token Sale Cells, and constraint 9 applies to each input/output pair in order.
+//! 16. If overpayment is allowed, the capacity difference of a purchase may be more than required by constraints 8 and 14, and the capacity difference of a buy-back may be less than required by constraints 13 and 14.
 
 #![no_std]
 #![no_main]
@@ -28,20 +45,24 @@
 // Import `Result` from `core` instead of from `std` since we are in no-std mode.
 use core::result::Result;
 
+// Import heap related library from `alloc`.
+// https://doc.rust-lang.org/alloc/index.html
+use alloc::vec;
+use alloc::vec::Vec;
+
 // Import CKB syscalls and structures.
 // https://nervosnetwork.github.io/ckb-std/riscv64imac-unknown-none-elf/doc/ckb_std/index.html
 // use ckb_std::{debug, default_alloc, entry};
 use ckb_std::{default_alloc, entry};
 use ckb_std::ckb_constants::Source;
-use ckb_std::ckb_types::{bytes::Bytes, packed::Bytes as Args, packed::Script, prelude::*};
+use ckb_std::ckb_types::{packed::CellOutput, packed::CellOutputReader, packed::Script as PackedScript, packed::ScriptReader, packed::WitnessArgs, packed::WitnessArgsReader, prelude::*};
 use ckb_std::error::{SysError};
-use ckb_std::high_level::{load_cell, load_cell_data, load_cell_lock_hash, load_script, QueryIter};
+use ckb_std::high_level::{load_cell_lock_hash, load_input_since, QueryIter, BUF_SIZE};
+use ckb_std::syscalls;
 
-// Constants
-const COST_AMOUNT_LEN: usize = 8; // Number of bytes for the token cost amount. (u64 8 bytes)
-const LOCK_HASH_LEN: usize = 32; // Number of bytes for a lock hash. (Blake2b 32 bytes)
-const SUDT_AMOUNT_DATA_LEN: usize = 16; // Number of bytes for an SUDT amount. (u128 16 bytes)
-const ARGS_LEN: usize = LOCK_HASH_LEN + COST_AMOUNT_LEN; // Number of bytes required for args. (40 bytes)
+// Import the shared validation logic and error codes.
+use token_sale_core::{determine_sale_options, validate, Cell, Script, LOCK_HASH_LEN, SUDT_AMOUNT_DATA_LEN};
+use token_sale_errors::Error;
 
 entry!(entry);
 default_alloc!();
@@ -57,239 +78,176 @@ fn entry() -> i8
 	}
 }
 
-/// Local error values.
-/// Low values are reserved for Sys Error codes.
-/// Values 100+ are for custom errors.
-#[repr(i8)]
-enum Error
+/// Map Sys Errors to local Error values.
+fn map_sys_error(err: SysError) -> Error
 {
-	IndexOutOfBound = 1,
-	ItemMissing,
-	LengthNotEnough,
-	Encoding,
-	ArgsLen = 100,
-	AmountCkbytes,
-	AmountSudt,
-	ExchangeRate,
-	InvalidCost,
-	InvalidStructure,
+	use SysError::*;
+	match err
+	{
+		IndexOutOfBound => Error::IndexOutOfBound,
+		ItemMissing => Error::ItemMissing,
+		LengthNotEnough(_) => Error::LengthNotEnough,
+		Encoding => Error::Encoding,
+		Unknown(err_code) => panic!("Unexpected Sys Error: {}", err_code),
+	}
 }
 
-/// Map Sys Errors to local Error values.
-impl From<SysError> for Error
+/// Load data of any length using the specified syscall.
+/// The loader in `ckb_std::high_level` panics when the data is larger than its buffer, which would allow any
+/// unrelated Cell with more than 1 KB of data to cause a purchase transaction to fail.
+fn load_data<F: Fn(&mut [u8], usize) -> Result<usize, SysError>>(syscall: F) -> Result<Vec<u8>, SysError>
 {
-	fn from(err: SysError) -> Self
+	let mut buf = [0u8; BUF_SIZE];
+	match syscall(&mut buf, 0)
 	{
-		use SysError::*;
-		match err
+		Ok(len) => Ok(buf[..len].to_vec()),
+		Err(SysError::LengthNotEnough(actual_size)) =>
 		{
-			IndexOutOfBound => Self::IndexOutOfBound,
-			ItemMissing => Self::ItemMissing,
-			LengthNotEnough(_) => Self::LengthNotEnough,
-			Encoding => Self::Encoding,
-			Unknown(err_code) => panic!("Unexpected Sys Error: {}", err_code),
-		}
+			let mut data = vec![0u8; actual_size];
+			data[..BUF_SIZE].copy_from_slice(&buf);
+			syscall(&mut data[BUF_SIZE..], BUF_SIZE)?;
+			Ok(data)
+		},
+		Err(err) => Err(err),
 	}
 }
 
-/// Determine if owner mode is enabled.
-fn check_owner_mode(args: &Args) -> Result<bool, Error>
+/// Load a Cell.
+fn load_cell(index: usize, source: Source) -> Result<CellOutput, SysError>
 {
-	// Compares the Lock Script Hash from the first 32 bytes of the args with the Lock Scripts
-	// of all input Cells to determine if a match exists.
-	let args: Bytes = args.unpack();
-	let is_owner_mode = QueryIter::new(load_cell_lock_hash, Source::Input)
-		.find(|lock_hash| args[0..LOCK_HASH_LEN] == lock_hash[..]).is_some();
+	let data = load_data(|buf, offset| syscalls::load_cell(buf, offset, index, source))?;
+	CellOutputReader::verify(&data, false).map_err(|_| SysError::Encoding)?;
 
-	Ok(is_owner_mode)
+	Ok(CellOutput::new_unchecked(data.into()))
 }
 
-/// Determine the capacity and token amount in all Cells matching the specified Lock Script and Type Script.
-fn determine_token_sale_cell_amounts(lock_script: &Script, type_script: &Script, source: Source) -> Result<(u64, u128), Error>
+/// Load the data of a Cell.
+fn load_cell_data(index: usize, source: Source) -> Result<Vec<u8>, SysError>
 {
-	let mut buf = [0u8; SUDT_AMOUNT_DATA_LEN];
-	let lock_script_bytes = &lock_script.as_bytes()[..];
-	let type_script_bytes = &type_script.as_bytes()[..];
+	load_data(|buf, offset| syscalls::load_cell_data(buf, offset, index, source))
+}
 
-	// Loop through all Cells in the specified source.
-	let mut total_capacity = 0;
-	let mut total_tokens = 0;
-	let mut i = 0;
-	loop
+/// Load the data of a Cell, truncated to the token amount.
+fn load_cell_data_amount(index: usize, source: Source) -> Result<Vec<u8>, SysError>
+{
+	let mut buf = [0u8; SUDT_AMOUNT_DATA_LEN];
+	match syscalls::load_cell_data(&mut buf, 0, index, source)
 	{
-		let cell = match load_cell(i, source)
-		{
-			Ok(cell) => cell,
-			Err(SysError::IndexOutOfBound) => break,
-			Err(e) => return Err(e.into()),
-		};
-
-		// Check if this Cell matches the Lock Script and Type Script.
-		let cell_lock_bytes = &cell.lock().as_bytes()[..];
-		let cell_type_bytes = &cell.type_().as_bytes()[..];
-		if cell_lock_bytes == lock_script_bytes && cell_type_bytes == type_script_bytes
-		{
-			// Ensure the Cell data is valid then add the capacity and token amount to the totals.
-			let data = load_cell_data(i, source)?;
-			if data.len() >= SUDT_AMOUNT_DATA_LEN
-			{
-				buf.copy_from_slice(&data[0..SUDT_AMOUNT_DATA_LEN]);
-				total_tokens += u128::from_le_bytes(buf);
-				total_capacity += cell.capacity().unpack();
-			}
-			else
-			{
-				return Err(Error::Encoding);
-			}
-		}
-
-		i += 1;
+		Ok(len) => Ok(buf[..len].to_vec()),
+		Err(SysError::LengthNotEnough(_)) => Ok(buf.to_vec()),
+		Err(err) => Err(err),
 	}
-
-	Ok((total_capacity, total_tokens))
 }
 
-/// Retrieve the token cost from the args.
-fn determine_token_cost(args: &Args) -> Result<u64, Error>
+/// Load the current script.
+fn load_script() -> Result<PackedScript, SysError>
 {
-	let args: Bytes = args.unpack();
-	let mut buf = [0u8; COST_AMOUNT_LEN];
-
-	// The token amount immediately follows the Lock Hash in the args.
-	let slice_start = LOCK_HASH_LEN;
-	let slice_end = slice_start + COST_AMOUNT_LEN;
-
-	// Copy bytes from the args into a u64. 
-	buf.copy_from_slice(&args[slice_start..slice_end]);
-	let token_cost = u64::from_le_bytes(buf);
+	let data = load_data(|buf, offset| syscalls::load_script(buf, offset))?;
+	ScriptReader::verify(&data, false).map_err(|_| SysError::Encoding)?;
 
-	if token_cost < 1
-	{
-		return Err(Error::InvalidCost);
-	}
-
-	Ok(token_cost)
+	Ok(PackedScript::new_unchecked(data.into()))
 }
 
-/// Ensure that all the capacity, token, and cost amounts are valid.
-fn validate_amounts(token_cost: u64, input_capacity_amount: u64, output_capacity_amount: u64, input_token_amount: u128, output_token_amount: u128) -> Result<(), Error>
+/// Load the lock field of the WitnessArgs of the first input Cell in the script group.
+/// A missing or malformed witness is treated as an empty lock field, since it is only required by some features.
+fn load_group_witness_lock() -> Vec<u8>
 {
-	// The output capacity must be more than the input capacity.
-	if output_capacity_amount <= input_capacity_amount
-	{
-		return Err(Error::AmountCkbytes);
-	}
-
-	// The output tokens must be less than the input tokens.
-	if output_token_amount >= input_token_amount
+	let data = match load_data(|buf, offset| syscalls::load_witness(buf, offset, 0, Source::GroupInput))
 	{
-		return Err(Error::AmountSudt);
-	}
-
-	// The capacity received must properly equate to the tokens sold at the proper token cost.
-	if (output_capacity_amount - input_capacity_amount) as u128 != (input_token_amount - output_token_amount) * token_cost as u128
+		Ok(data) => data,
+		Err(_) => return vec![],
+	};
+	if WitnessArgsReader::verify(&data, false).is_err()
 	{
-		return Err(Error::ExchangeRate);
+		return vec![];
 	}
 
-	Ok(())
+	WitnessArgs::new_unchecked(data.into()).lock().to_opt().map(|lock| lock.raw_data().to_vec()).unwrap_or_default()
 }
 
-/// Ensure that a valid input Token Sale Cell exists.
-fn validate_token_sale_inputs() -> Result<(Script, Script), Error>
+/// All Cells loaded from a source, held so that views of them can be passed to the validation core.
+struct LoadedCells
 {
-	// Verify that index 1 does not exist.
-	if load_cell(1, Source::GroupInput).is_ok()
-	{
-		return Err(Error::InvalidStructure);
-	}
-
-	// Load the Token Sale Cell. There should be exactly 1.
-	let token_sale_cell = load_cell(0, Source::GroupInput)?;
-
-	// Extract the Scripts. Both must exist.
-	let lock_script = token_sale_cell.lock();
-	let type_script = token_sale_cell.type_().to_opt().ok_or(Error::InvalidStructure)?;
-
-	Ok((lock_script, type_script))
+	cells: Vec<CellOutput>,
+	data: Vec<Vec<u8>>,
+	lock_hashes: Vec<[u8; LOCK_HASH_LEN]>,
+	since: Vec<u64>,
 }
 
-/// Ensure that a valid output Token Sale Cell exists.
-fn validate_token_sale_outputs(lock_script: &Script, type_script: &Script) -> Result<(), Error>
+impl LoadedCells
 {
-	let lock_script_bytes = &lock_script.as_bytes()[..];
-	let type_script_bytes = &type_script.as_bytes()[..];
-
-	// Loop through all the output Cells.
-	let mut i = 0;
-	let mut token_sale_lock_cells = 0;
-	loop
+	/// Load all Cells from the specified source.
+	/// Only the token amount is loaded from the data of Cells which do not use the specified Lock Script, so that
+	/// unrelated Cells with large amounts of data cannot exhaust the heap.
+	fn load(source: Source, lock_script: &Script) -> Result<Self, SysError>
 	{
-		let cell = match load_cell(i, Source::Output)
-		{
-			Ok(cell) => cell,
-			Err(SysError::IndexOutOfBound) => break,
-			Err(e) => return Err(e.into()),
-		};
+		let cells: Vec<CellOutput> = QueryIter::new(load_cell, source).collect();
+		let data = cells.iter().enumerate()
+			.map(|(index, cell)| if script_view(cell.as_reader().lock()) == *lock_script { load_cell_data(index, source) } else { load_cell_data_amount(index, source) })
+			.collect::<Result<_, _>>()?;
 
-		// Count up matching Token Sale Cells with a matching SUDT Type Script.
-		let cell_lock_bytes = &cell.lock().as_bytes()[..];
-		let cell_type_bytes = &cell.type_().as_bytes()[..];
-		if cell_lock_bytes == lock_script_bytes && cell_type_bytes == type_script_bytes
+		Ok(Self
 		{
-			token_sale_lock_cells += 1;
-		}
-
-		i += 1;
+			cells,
+			data,
+			lock_hashes: QueryIter::new(load_cell_lock_hash, source).collect(),
+			since: if source == Source::Input { QueryIter::new(load_input_since, source).collect() } else { vec![] },
+		})
 	}
 
-	// debug!("Total Token Sale Lock Cells: {}", token_sale_lock_cells);
-
-	// There must be exactly one output Token Sale Lock Cell and it must have a Type Script matching the input Token Sale Lock Cell.
-	if token_sale_lock_cells != 1
+	/// Create views of the loaded Cells for the validation core.
+	/// The witness lock is attached to the Cells which use the specified Lock Script.
+	fn views<'a>(&'a self, lock_script: &Script, witness_lock: &'a [u8]) -> Vec<Cell<'a>>
 	{
-		return Err(Error::InvalidStructure);
+		// Output Cells have no since value.
+		let since = self.since.iter().copied().chain(core::iter::repeat(0));
+		self.cells.iter().zip(self.data.iter()).zip(self.lock_hashes.iter()).zip(since)
+			.map(|(((cell, data), lock_hash), since)|
+			{
+				let cell = cell.as_reader();
+				let lock = script_view(cell.lock());
+				Cell
+				{
+					capacity: cell.capacity().unpack(),
+					lock,
+					lock_hash: &lock_hash[..],
+					type_: cell.type_().to_opt().map(script_view),
+					data: &data[..],
+					since,
+					witness_lock: if lock == *lock_script { witness_lock } else { &[] },
+				}
+			})
+			.collect()
 	}
-
-	Ok(())
 }
 
-fn main() -> Result<(), Error>
+/// Create a view of a Script for the validation core.
+fn script_view<'a>(script: ScriptReader<'a>) -> Script<'a>
 {
-	// Load arguments from the current script.
-	let script = load_script()?;
-	let args = script.args();
-
-	// Verify that the minimum length of the arguments was given.
-	if args.len() < ARGS_LEN
-	{
-		return Err(Error::ArgsLen);
-	}
-
-	// If program is in owner mode then unlock immediately.
-	if check_owner_mode(&args)?
+	Script
 	{
-		// debug!("Token Sale owner mode enabled.");
-		return Ok(());
+		code_hash: script.code_hash().raw_data(),
+		hash_type: script.hash_type().as_slice()[0],
+		args: script.args().raw_data(),
 	}
+}
 
-	// Check the inputs to ensure there is a single input Token Sale Cell.
-	let (lock_script, type_script) = validate_token_sale_inputs()?;
-
-	// Check the outputs to ensure there is a single output Token Sale Cell.
-	validate_token_sale_outputs(&lock_script, &type_script)?;
+fn main() -> Result<(), Error>
+{
+	// Load the current script and all the Cells in the transaction.
+	let packed_script = load_script().map_err(map_sys_error)?;
+	let script = script_view(packed_script.as_reader());
+	let inputs = LoadedCells::load(Source::Input, &script).map_err(map_sys_error)?;
+	let outputs = LoadedCells::load(Source::Output, &script).map_err(map_sys_error)?;
 
-	// Find all the capacity, token, and cost amounts.
-	let token_cost = determine_token_cost(&args)?;
-	let (input_capacity_amount, input_token_amount) = determine_token_sale_cell_amounts(&lock_script, &type_script, Source::GroupInput)?;
-	let (output_capacity_amount, output_token_amount) = determine_token_sale_cell_amounts(&lock_script, &type_script, Source::Output)?;
+	// The witness is only loaded when the whitelist requires a proof.
+	let whitelist = determine_sale_options(script.args).map(|options| options.whitelist_root.is_some()).unwrap_or(false);
+	let witness_lock = if whitelist { load_group_witness_lock() } else { vec![] };
 
-	// debug!("Token Cost: {}", token_cost);
-	// debug!("Input/Output Capacity: {}/{}", input_capacity_amount, output_capacity_amount);
-	// debug!("Input/Output Token Amount: {}/{}", input_token_amount, output_token_amount);
+	// debug!("Input/Output Cells: {}/{}", inputs.cells.len(), outputs.cells.len());
 
-	// Validate that all amounts are in balance.
-	validate_amounts(token_cost, input_capacity_amount, output_capacity_amount, input_token_amount, output_token_amount)?;
+	// Validate the script group of the current script.
+	validate(&script, &inputs.views(&script, &witness_lock), &outputs.views(&script, &[]))?;
 
 	Ok(())
 }
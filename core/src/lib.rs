@@ -0,0 +1,697 @@
+//! Simple Token Sale Validation Core
+//! https://github.com/jordanmack/token-sale
+//!
+//! The validation logic of the Token Sale Lock Script, shared by the on-chain contract and host-side tooling.
+//!
+//! The contract loads the Cells of the transaction using syscalls and passes them to `validate()`. Services can pass
+//! the resolved Cells of a transaction to `validate_transaction()` to verify that a transaction will pass the
+//! Token Sale Lock Script before broadcasting it. Both return the same error codes.
+//!
+//! Token Compatibility
+//! The token must store its amount as a u128 LE in the first 16 bytes of the Cell data, as both SUDT and xUDT do.
+//! Every xUDT configuration is supported: the owner mode flags, extension scripts in the args (flags 1), and extension
+//! script hashes (flags 2). The Type Script args are never parsed. Only the lock field of the WitnessArgs is read, so
+//! the extension data in the input_type and output_type fields is ignored. The token script enforces its own
+//! configuration, and the Lock Script only ensures that a purchase does not alter it:
+//! - An output Token Sale Cell whose Type Script differs in any way, including xUDT flags or extension args, is
+//!   rejected with `InvalidStructure`.
+//! - An output Token Sale Cell whose data following the amount differs, including xUDT data, is rejected with
+//!   `InvalidStructure`.
+//! - Data shorter than 16 bytes is rejected with `Encoding`.
+
+#![no_std]
+
+// Import `Result` from `core` instead of from `std` since we are in no-std mode.
+use core::result::Result;
+
+// Import the hash function used for Merkle proofs.
+use blake2b_ref::Blake2bBuilder;
+
+// Re-export the shared error codes.
+pub use token_sale_errors::Error;
+
+// Constants
+pub const COST_AMOUNT_LEN: usize = 8; // Number of bytes for the token cost amount. (u64 8 bytes)
+pub const LOCK_HASH_LEN: usize = 32; // Number of bytes for a lock hash. (Blake2b 32 bytes)
+pub const SUDT_AMOUNT_DATA_LEN: usize = 16; // Number of bytes for an SUDT amount. (u128 16 bytes)
+pub const ARGS_LEN: usize = LOCK_HASH_LEN + COST_AMOUNT_LEN; // Number of bytes required for args. (40 bytes)
+pub const ID_LEN: usize = 4; // Number of bytes for the unique identifier when flags are used. (u32 4 bytes)
+pub const FLAGS_LEN: usize = 1; // Number of bytes for the flags. (u8 1 byte)
+pub const FLAGS_OFFSET: usize = ARGS_LEN + ID_LEN; // The position of the flags in the args. (44 bytes)
+pub const EPOCH_LEN: usize = 8; // Number of bytes for an epoch. (u64 LE 8 bytes)
+pub const HASH_LEN: usize = 32; // Number of bytes for a Merkle root or proof hash. (Blake2b 32 bytes)
+pub const WHITELIST_INDEX_LEN: usize = 4; // Number of bytes for the whitelisted input index in the witness lock. (u32 4 bytes)
+pub const HASH_PERSONALIZATION: &[u8] = b"ckb-default-hash"; // The Blake2b personalization used by CKB.
+pub const FLAG_SALE_START: u8 = 0x01; // Purchases are only allowed from the sale start epoch onward.
+pub const FLAG_WHITELIST: u8 = 0x04; // Purchases require a Merkle proof that the lock receiving the tokens is whitelisted.
+pub const FLAG_BUY_BACK: u8 = 0x08; // Tokens can be sold back to the Token Sale Cell at the buy-back cost.
+pub const FLAG_PRICE_DENOMINATOR: u8 = 0x10; // The costs are per denominator tokens instead of per token.
+pub const FLAG_MULTIPLE_CELLS: u8 = 0x20; // Multiple Token Sale Cells with identical args can be used in one transaction.
+pub const FLAG_OVERPAYMENT: u8 = 0x40; // The exchange may favor the Token Sale Cell, leaving the excess capacity in it.
+pub const FLAGS_SUPPORTED: u8 = FLAG_SALE_START | FLAG_WHITELIST | FLAG_BUY_BACK | FLAG_PRICE_DENOMINATOR | FLAG_MULTIPLE_CELLS | FLAG_OVERPAYMENT; // All flags recognized by this version. (0x02 is reserved)
+pub const SHANNONS_PER_BYTE: u64 = 100_000_000; // Number of Shannons required for each byte a Cell occupies. (1 CKByte)
+pub const CAPACITY_OCCUPIED_LEN: usize = 8; // Number of bytes the capacity of a Cell occupies. (u64 8 bytes)
+pub const SCRIPT_OCCUPIED_LEN: usize = 33; // Number of bytes a Script occupies excluding the args. (Code hash 32 bytes, hash type 1 byte)
+pub const SINCE_RELATIVE_FLAG: u64 = 0x8000_0000_0000_0000; // The since flag for a relative value.
+pub const SINCE_METRIC_MASK: u64 = 0x6000_0000_0000_0000; // The since bits which select the metric.
+pub const SINCE_METRIC_EPOCH: u64 = 0x2000_0000_0000_0000; // The since metric for an epoch.
+pub const SINCE_VALUE_MASK: u64 = 0x00FF_FFFF_FFFF_FFFF; // The since bits which hold the value.
+
+/// A borrowed view of a Script.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Script<'a>
+{
+	pub code_hash: &'a [u8],
+	pub hash_type: u8,
+	pub args: &'a [u8],
+}
+
+/// A borrowed view of a resolved Cell.
+#[derive(Clone, Copy, Debug)]
+pub struct Cell<'a>
+{
+	pub capacity: u64,
+	pub lock: Script<'a>,
+	pub lock_hash: &'a [u8],
+	pub type_: Option<Script<'a>>,
+	/// The data of the Cell. The contract only loads the token amount from the data of a Cell which does not use the
+	/// Lock Script being validated, so nothing beyond it may be read from such a Cell.
+	pub data: &'a [u8],
+	/// The since value of an input Cell, or 0 for an output Cell.
+	pub since: u64,
+	/// The lock field of the WitnessArgs at the index of an input Cell. Empty when absent, or for an output Cell.
+	pub witness_lock: &'a [u8],
+}
+
+/// The optional features of a Token Sale Cell, parsed from the flags and the fields which follow them in the args.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SaleOptions
+{
+	pub flags: u8,
+	pub sale_start: Option<u64>,
+	pub whitelist_root: Option<[u8; HASH_LEN]>,
+	pub buy_back_cost: Option<u64>,
+	pub price_denominator: Option<u64>,
+}
+
+/// A quote for a purchase from a Token Sale Cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quote
+{
+	/// The capacity in Shannons which the buyer must add to the Token Sale Cell.
+	pub capacity_required: u64,
+	/// The capacity of the output Token Sale Cell.
+	pub output_capacity: u64,
+	/// The tokens in the output Token Sale Cell.
+	pub output_tokens: u128,
+}
+
+/// Determine if owner mode is enabled.
+pub fn check_owner_mode(args: &[u8], inputs: &[Cell]) -> bool
+{
+	// Compares the Lock Script Hash from the first 32 bytes of the args with the Lock Scripts
+	// of all input Cells to determine if a match exists.
+	inputs.iter().any(|cell| args[0..LOCK_HASH_LEN] == cell.lock_hash[..])
+}
+
+/// Determine the capacity and token amount in all Cells matching the specified Lock Script and Type Script.
+pub fn determine_token_sale_cell_amounts(lock_script: &Script, type_script: &Script, cells: &[Cell]) -> Result<(u64, u128), Error>
+{
+	let mut buf = [0u8; SUDT_AMOUNT_DATA_LEN];
+
+	// Loop through all the specified Cells.
+	let mut total_capacity: u64 = 0;
+	let mut total_tokens: u128 = 0;
+	for cell in cells
+	{
+		// Check if this Cell matches the Lock Script and Type Script.
+		if cell.lock == *lock_script && cell.type_ == Some(*type_script)
+		{
+			// Ensure the Cell data is valid then add the capacity and token amount to the totals.
+			if cell.data.len() >= SUDT_AMOUNT_DATA_LEN
+			{
+				// The totals of multiple Token Sale Cells cannot overflow on-chain, but are checked for host-side callers.
+				buf.copy_from_slice(&cell.data[0..SUDT_AMOUNT_DATA_LEN]);
+				total_tokens = total_tokens.checked_add(u128::from_le_bytes(buf)).ok_or(Error::AmountSudt)?;
+				total_capacity = total_capacity.checked_add(cell.capacity).ok_or(Error::AmountCkbytes)?;
+			}
+			else
+			{
+				return Err(Error::Encoding);
+			}
+		}
+	}
+
+	Ok((total_capacity, total_tokens))
+}
+
+/// Retrieve the token cost from the args.
+pub fn determine_token_cost(args: &[u8]) -> Result<u64, Error>
+{
+	let mut buf = [0u8; COST_AMOUNT_LEN];
+
+	// The token amount immediately follows the Lock Hash in the args.
+	let slice_start = LOCK_HASH_LEN;
+	let slice_end = slice_start + COST_AMOUNT_LEN;
+
+	// Copy bytes from the args into a u64.
+	buf.copy_from_slice(&args[slice_start..slice_end]);
+	let token_cost = u64::from_le_bytes(buf);
+
+	if token_cost < 1
+	{
+		return Err(Error::InvalidCost);
+	}
+
+	Ok(token_cost)
+}
+
+/// Retrieve the optional features from the args.
+///
+/// Args of 44 bytes or fewer have no flags. Otherwise the flags follow the 4 byte identifier, and the field of each flag
+/// which is set follows in the order of the flag bits. The args must end after the last field.
+pub fn determine_sale_options(args: &[u8]) -> Result<SaleOptions, Error>
+{
+	let mut options = SaleOptions::default();
+	if args.len() <= FLAGS_OFFSET
+	{
+		return Ok(options);
+	}
+
+	// Unknown flags are rejected so that a future version cannot be misread.
+	options.flags = args[FLAGS_OFFSET];
+	if options.flags & !FLAGS_SUPPORTED != 0
+	{
+		return Err(Error::InvalidFlags);
+	}
+
+	// Read the field of each flag which is set.
+	let mut fields = &args[FLAGS_OFFSET + FLAGS_LEN..];
+	if options.flags & FLAG_SALE_START != 0
+	{
+		options.sale_start = Some(read_u64(&mut fields)?);
+	}
+	if options.flags & FLAG_WHITELIST != 0
+	{
+		let mut root = [0u8; HASH_LEN];
+		root.copy_from_slice(read_field(&mut fields, HASH_LEN)?);
+		options.whitelist_root = Some(root);
+	}
+	if options.flags & FLAG_BUY_BACK != 0
+	{
+		// A buy-back cost above the cost would allow anyone to drain the capacity by buying and selling back tokens.
+		let buy_back_cost = read_u64(&mut fields)?;
+		if buy_back_cost < 1 || buy_back_cost > determine_token_cost(args)?
+		{
+			return Err(Error::InvalidCost);
+		}
+		options.buy_back_cost = Some(buy_back_cost);
+	}
+	if options.flags & FLAG_PRICE_DENOMINATOR != 0
+	{
+		let price_denominator = read_u64(&mut fields)?;
+		if price_denominator < 1
+		{
+			return Err(Error::InvalidCost);
+		}
+		options.price_denominator = Some(price_denominator);
+	}
+
+	if !fields.is_empty()
+	{
+		return Err(Error::ArgsLen);
+	}
+
+	Ok(options)
+}
+
+/// Read a field of the specified length from the front of the fields and advance past it.
+fn read_field<'a>(fields: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error>
+{
+	if fields.len() < len
+	{
+		return Err(Error::ArgsLen);
+	}
+	let (field, remaining) = fields.split_at(len);
+	*fields = remaining;
+
+	Ok(field)
+}
+
+/// Read a u64 LE from the front of the fields and advance past it.
+fn read_u64(fields: &mut &[u8]) -> Result<u64, Error>
+{
+	let mut buf = [0u8; core::mem::size_of::<u64>()];
+	buf.copy_from_slice(read_field(fields, core::mem::size_of::<u64>())?);
+
+	Ok(u64::from_le_bytes(buf))
+}
+
+/// Compare two epochs with fractions, as stored in a since value or header.
+pub fn compare_epochs(a: u64, b: u64) -> core::cmp::Ordering
+{
+	// An epoch is a number (24 bits), an index (16 bits), and a length (16 bits). A length of 0 has no fraction.
+	let fraction = |epoch: u64|
+	{
+		let number = (epoch & 0xFF_FFFF) as u128;
+		let index = ((epoch >> 24) & 0xFFFF) as u128;
+		let length = ((epoch >> 40) & 0xFFFF) as u128;
+		if length == 0 { (number, 1) } else { (number * length + index, length) }
+	};
+	let (a_numerator, a_denominator) = fraction(a);
+	let (b_numerator, b_denominator) = fraction(b);
+
+	(a_numerator * b_denominator).cmp(&(b_numerator * a_denominator))
+}
+
+/// Ensure that the purchase is made on or after the sale start.
+///
+/// CKB will not include a transaction before the since value of an input, so the since of the input Token Sale Cell
+/// must be an absolute epoch on or after the start.
+pub fn validate_sale_start(options: &SaleOptions, lock_script: &Script, inputs: &[Cell]) -> Result<(), Error>
+{
+	let sale_start = match options.sale_start
+	{
+		Some(sale_start) => sale_start,
+		None => return Ok(()),
+	};
+
+	// The since must be an absolute epoch.
+	let token_sale_cell = inputs.iter().find(|cell| cell.lock == *lock_script).ok_or(Error::IndexOutOfBound)?;
+	let since = token_sale_cell.since;
+	if since & SINCE_RELATIVE_FLAG != 0 || since & SINCE_METRIC_MASK != SINCE_METRIC_EPOCH
+	{
+		return Err(Error::SaleWindow);
+	}
+	let epoch = since & SINCE_VALUE_MASK;
+
+	if compare_epochs(epoch, sale_start) == core::cmp::Ordering::Less
+	{
+		return Err(Error::SaleWindow);
+	}
+
+	Ok(())
+}
+
+/// Calculate the Merkle root of a leaf from a proof consisting of the sibling hash at each level.
+///
+/// Each pair of nodes is hashed in sorted order, so the proof does not need to record the position of the leaf.
+/// Returns `None` if the leaf or the proof is malformed.
+pub fn calculate_merkle_root(leaf: &[u8], proof: &[u8]) -> Option<[u8; HASH_LEN]>
+{
+	let siblings = proof.chunks_exact(HASH_LEN);
+	if leaf.len() != HASH_LEN || !siblings.remainder().is_empty()
+	{
+		return None;
+	}
+
+	let mut node = [0u8; HASH_LEN];
+	node.copy_from_slice(leaf);
+	for sibling in siblings
+	{
+		let (left, right) = if node[..] <= *sibling { (&node[..], sibling) } else { (sibling, &node[..]) };
+		let mut blake2b = Blake2bBuilder::new(HASH_LEN).personal(HASH_PERSONALIZATION).build();
+		blake2b.update(left);
+		blake2b.update(right);
+		let mut parent = [0u8; HASH_LEN];
+		blake2b.finalize(&mut parent);
+		node = parent;
+	}
+
+	Some(node)
+}
+
+/// Determine the token amount in all Cells matching the specified lock hash and Type Script.
+pub fn determine_lock_hash_token_amount(lock_hash: &[u8], type_script: &Script, cells: &[Cell]) -> Result<u128, Error>
+{
+	let mut buf = [0u8; SUDT_AMOUNT_DATA_LEN];
+
+	let mut total_tokens: u128 = 0;
+	for cell in cells.iter().filter(|cell| cell.lock_hash == lock_hash && cell.type_ == Some(*type_script))
+	{
+		if cell.data.len() < SUDT_AMOUNT_DATA_LEN
+		{
+			return Err(Error::Encoding);
+		}
+		buf.copy_from_slice(&cell.data[0..SUDT_AMOUNT_DATA_LEN]);
+		total_tokens = total_tokens.checked_add(u128::from_le_bytes(buf)).ok_or(Error::AmountSudt)?;
+	}
+
+	Ok(total_tokens)
+}
+
+/// Ensure that the buyer is whitelisted.
+///
+/// The witness lock of the input Token Sale Cell must hold the index of an input Cell as a u32 LE, followed by a Merkle
+/// proof which leads from the lock hash of that input Cell to the whitelist root. The whitelisted lock must receive all
+/// the tokens sold by the Token Sale Cells, or provide all the tokens bought back. A proof for a lock which anyone can
+/// add to a transaction, such as an anyone-can-pay lock, therefore only ever benefits its holder.
+pub fn validate_whitelist(options: &SaleOptions, lock_script: &Script, type_script: &Script, inputs: &[Cell], outputs: &[Cell], input_token_amount: u128, output_token_amount: u128) -> Result<(), Error>
+{
+	let whitelist_root = match options.whitelist_root
+	{
+		Some(whitelist_root) => whitelist_root,
+		None => return Ok(()),
+	};
+
+	// Find the whitelisted input Cell and verify the proof of its lock hash.
+	let token_sale_cell = inputs.iter().find(|cell| cell.lock == *lock_script).ok_or(Error::IndexOutOfBound)?;
+	let witness_lock = token_sale_cell.witness_lock;
+	if witness_lock.len() < WHITELIST_INDEX_LEN
+	{
+		return Err(Error::Whitelist);
+	}
+	let mut buf = [0u8; WHITELIST_INDEX_LEN];
+	buf.copy_from_slice(&witness_lock[0..WHITELIST_INDEX_LEN]);
+	let whitelisted_cell = inputs.get(u32::from_le_bytes(buf) as usize).ok_or(Error::Whitelist)?;
+	if calculate_merkle_root(whitelisted_cell.lock_hash, &witness_lock[WHITELIST_INDEX_LEN..]) != Some(whitelist_root)
+	{
+		return Err(Error::Whitelist);
+	}
+
+	// The tokens of the whitelisted lock must change by at least the tokens exchanged with the Token Sale Cells.
+	let whitelisted_input_tokens = determine_lock_hash_token_amount(whitelisted_cell.lock_hash, type_script, inputs)?;
+	let whitelisted_output_tokens = determine_lock_hash_token_amount(whitelisted_cell.lock_hash, type_script, outputs)?;
+	let (required_tokens, whitelisted_tokens) = if output_token_amount < input_token_amount
+	{
+		(whitelisted_input_tokens.checked_add(input_token_amount - output_token_amount), whitelisted_output_tokens)
+	}
+	else
+	{
+		(whitelisted_output_tokens.checked_add(output_token_amount - input_token_amount), whitelisted_input_tokens)
+	};
+	match required_tokens
+	{
+		Some(required_tokens) if whitelisted_tokens >= required_tokens => (),
+		_ => return Err(Error::Whitelist),
+	}
+
+	Ok(())
+}
+
+/// Ensure that all the capacity, token, and cost amounts are valid.
+///
+/// The token cost is the price of the specified number of tokens, which is 1 unless a price denominator is set.
+/// If overpayment is allowed, the capacity received may be more than the cost of the tokens sold.
+pub fn validate_amounts(token_cost: u64, price_denominator: u64, overpayment: bool, input_capacity_amount: u64, output_capacity_amount: u64, input_token_amount: u128, output_token_amount: u128) -> Result<(), Error>
+{
+	// The output capacity must be more than the input capacity.
+	if output_capacity_amount <= input_capacity_amount
+	{
+		return Err(Error::AmountCkbytes);
+	}
+
+	// The output tokens must be less than the input tokens.
+	if output_token_amount >= input_token_amount
+	{
+		return Err(Error::AmountSudt);
+	}
+
+	// The capacity received must properly equate to the tokens sold at the proper token cost.
+	// Both sides are multiplied by the denominator instead of dividing, so no precision is lost. The capacity side is
+	// a u64 multiplied by a u64, which always fits in a u128. A token side which overflows a u128 can never be paid.
+	let capacity_received = (output_capacity_amount - input_capacity_amount) as u128 * price_denominator as u128;
+	let capacity_required = (input_token_amount - output_token_amount).checked_mul(token_cost as u128).ok_or(Error::ExchangeRate)?;
+	if capacity_received < capacity_required || (capacity_received > capacity_required && !overpayment)
+	{
+		return Err(Error::ExchangeRate);
+	}
+
+	Ok(())
+}
+
+/// Quote a purchase of the specified number of tokens from a Token Sale Cell with the specified capacity and tokens.
+///
+/// The capacity required is the exact amount which the buyer must add to the Token Sale Cell. If overpayment is allowed
+/// and the cost of the tokens is a fraction of a Shannon, it is rounded up. Otherwise the purchase is rejected with
+/// `ExchangeRate`, since it can never pass `validate_amounts()`.
+pub fn quote_purchase(args: &[u8], capacity: u64, tokens: u128, tokens_bought: u128) -> Result<Quote, Error>
+{
+	if args.len() < ARGS_LEN
+	{
+		return Err(Error::ArgsLen);
+	}
+	let options = determine_sale_options(args)?;
+	let token_cost = determine_token_cost(args)?;
+	let price_denominator = options.price_denominator.unwrap_or(1) as u128;
+
+	// At least one token must be bought, and no more than the Token Sale Cell holds.
+	if tokens_bought < 1 || tokens_bought > tokens
+	{
+		return Err(Error::AmountSudt);
+	}
+
+	// The capacity required is the tokens bought multiplied by the cost, divided by the denominator.
+	let cost = tokens_bought.checked_mul(token_cost as u128).ok_or(Error::ExchangeRate)?;
+	let remainder = cost % price_denominator;
+	if remainder != 0 && options.flags & FLAG_OVERPAYMENT == 0
+	{
+		return Err(Error::ExchangeRate);
+	}
+	let capacity_required = cost / price_denominator + if remainder != 0 { 1 } else { 0 };
+	if capacity_required > u64::MAX as u128
+	{
+		return Err(Error::ExchangeRate);
+	}
+	let capacity_required = capacity_required as u64;
+
+	Ok(Quote
+	{
+		capacity_required,
+		output_capacity: capacity.checked_add(capacity_required).ok_or(Error::AmountCkbytes)?,
+		output_tokens: tokens - tokens_bought,
+	})
+}
+
+/// Calculate the minimum capacity of a Cell in Shannons, from the lengths of its Lock Script args, Type Script args,
+/// and data. Pass `None` for a Cell without a Type Script.
+///
+/// A buyer must provide at least this much capacity for the Cell which receives the tokens, in addition to the
+/// capacity required by the quote.
+pub fn determine_occupied_capacity(lock_args_len: usize, type_args_len: Option<usize>, data_len: usize) -> u64
+{
+	let script_len = |args_len: usize| SCRIPT_OCCUPIED_LEN + args_len;
+	let occupied_len = CAPACITY_OCCUPIED_LEN + script_len(lock_args_len) + type_args_len.map_or(0, script_len) + data_len;
+
+	occupied_len as u64 * SHANNONS_PER_BYTE
+}
+
+/// Ensure that all the capacity, token, and buy-back cost amounts are valid when tokens are sold back.
+///
+/// The buy-back cost is the price of the specified number of tokens, which is 1 unless a price denominator is set.
+/// If overpayment is allowed, the capacity paid may be less than the buy-back cost of the tokens returned.
+pub fn validate_buy_back_amounts(buy_back_cost: u64, price_denominator: u64, overpayment: bool, input_capacity_amount: u64, output_capacity_amount: u64, input_token_amount: u128, output_token_amount: u128) -> Result<(), Error>
+{
+	// The output capacity must be less than the input capacity.
+	if output_capacity_amount >= input_capacity_amount
+	{
+		return Err(Error::AmountCkbytes);
+	}
+
+	// The output tokens must be more than the input tokens.
+	if output_token_amount <= input_token_amount
+	{
+		return Err(Error::AmountSudt);
+	}
+
+	// The capacity paid must properly equate to the tokens returned at the proper buy-back cost.
+	// Both sides are multiplied by the denominator in the same way as a purchase. A token side which overflows a u128
+	// is owed more than any capacity paid, which is only valid with overpayment.
+	let capacity_paid = (input_capacity_amount - output_capacity_amount) as u128 * price_denominator as u128;
+	let capacity_owed = match (output_token_amount - input_token_amount).checked_mul(buy_back_cost as u128)
+	{
+		Some(capacity_owed) => capacity_owed,
+		None if overpayment => return Ok(()),
+		None => return Err(Error::ExchangeRate),
+	};
+	if capacity_paid > capacity_owed || (capacity_paid < capacity_owed && !overpayment)
+	{
+		return Err(Error::ExchangeRate);
+	}
+
+	Ok(())
+}
+
+/// Ensure that a valid input Token Sale Cell exists and return its Type Script.
+///
+/// There must be exactly one input Token Sale Cell, unless multiple Cells are enabled. Every input Token Sale Cell must
+/// then have the same Type Script.
+pub fn validate_token_sale_inputs<'a>(options: &SaleOptions, lock_script: &Script, inputs: &[Cell<'a>]) -> Result<Script<'a>, Error>
+{
+	// Load the first Token Sale Cell. There should be at least 1.
+	let mut group_inputs = inputs.iter().filter(|cell| cell.lock == *lock_script);
+	let token_sale_cell = group_inputs.next().ok_or(Error::IndexOutOfBound)?;
+
+	// Extract the Type Script. It must exist.
+	let type_script = token_sale_cell.type_.ok_or(Error::InvalidStructure)?;
+
+	// Any other Token Sale Cells must be allowed and hold the same token.
+	for cell in group_inputs
+	{
+		if options.flags & FLAG_MULTIPLE_CELLS == 0 || cell.type_ != Some(type_script)
+		{
+			return Err(Error::InvalidStructure);
+		}
+	}
+
+	Ok(type_script)
+}
+
+/// Ensure that a valid output Token Sale Cell exists for each input Token Sale Cell.
+pub fn validate_token_sale_outputs(lock_script: &Script, type_script: &Script, inputs: &[Cell], outputs: &[Cell]) -> Result<(), Error>
+{
+	// There should be exactly as many output Token Sale Cells as input Token Sale Cells.
+	let input_count = inputs.iter().filter(|cell| cell.lock == *lock_script).count();
+	let mut output_count = 0;
+	for cell in outputs.iter().filter(|cell| cell.lock == *lock_script)
+	{
+		// The Type Script must match the input Token Sale Cells.
+		if cell.type_ != Some(*type_script)
+		{
+			return Err(Error::InvalidStructure);
+		}
+		output_count += 1;
+	}
+	if output_count == 0 || output_count != input_count
+	{
+		return Err(Error::InvalidStructure);
+	}
+
+	Ok(())
+}
+
+/// Ensure that the data following the token amount is unchanged between the input and output Token Sale Cells.
+///
+/// When there are multiple Token Sale Cells, each input is paired with the output at the same position in the group.
+pub fn validate_token_sale_data(lock_script: &Script, inputs: &[Cell], outputs: &[Cell]) -> Result<(), Error>
+{
+	let mut group_inputs = inputs.iter().filter(|cell| cell.lock == *lock_script);
+	let mut group_outputs = outputs.iter().filter(|cell| cell.lock == *lock_script);
+	let mut pairs = 0;
+	loop
+	{
+		let (input_cell, output_cell) = match (group_inputs.next(), group_outputs.next())
+		{
+			(Some(input_cell), Some(output_cell)) => (input_cell, output_cell),
+			(None, None) if pairs > 0 => break,
+			_ => return Err(Error::InvalidStructure),
+		};
+
+		// A buyer must not be able to append, remove, or alter any data other than the token amount.
+		if input_cell.data.get(SUDT_AMOUNT_DATA_LEN..) != output_cell.data.get(SUDT_AMOUNT_DATA_LEN..)
+		{
+			return Err(Error::InvalidStructure);
+		}
+		pairs += 1;
+	}
+
+	Ok(())
+}
+
+/// Ensure that a migration recreates every Token Sale Cell under the new Token Sale Lock Script without changes.
+///
+/// A migration is made by the owner in owner mode, which the Lock Script unlocks unconditionally, so this is only
+/// checked by the owner's tooling before the transaction is broadcast. Each input Cell using the old code hash and hash
+/// type is paired with the output Cell using the new code hash and hash type at the same position. Every pair must have
+/// identical args, Type Script, and data, which preserves the owner, cost, identifier, flags, and tokens.
+pub fn validate_migration(old_code_hash: &[u8], old_hash_type: u8, new_code_hash: &[u8], new_hash_type: u8, inputs: &[Cell], outputs: &[Cell]) -> Result<(), Error>
+{
+	let mut old_cells = inputs.iter().filter(|cell| cell.lock.code_hash == old_code_hash && cell.lock.hash_type == old_hash_type);
+	let mut new_cells = outputs.iter().filter(|cell| cell.lock.code_hash == new_code_hash && cell.lock.hash_type == new_hash_type);
+	let mut pairs = 0;
+	loop
+	{
+		let (old_cell, new_cell) = match (old_cells.next(), new_cells.next())
+		{
+			(Some(old_cell), Some(new_cell)) => (old_cell, new_cell),
+			(None, None) if pairs > 0 => break,
+			_ => return Err(Error::InvalidStructure),
+		};
+
+		if old_cell.lock.args != new_cell.lock.args || old_cell.type_ != new_cell.type_ || old_cell.data != new_cell.data
+		{
+			return Err(Error::InvalidStructure);
+		}
+		pairs += 1;
+	}
+
+	Ok(())
+}
+
+/// Validate the script group of the specified Token Sale Lock Script.
+pub fn validate(lock_script: &Script, inputs: &[Cell], outputs: &[Cell]) -> Result<(), Error>
+{
+	let args = lock_script.args;
+
+	// Verify that the minimum length of the arguments was given.
+	if args.len() < ARGS_LEN
+	{
+		return Err(Error::ArgsLen);
+	}
+
+	// If program is in owner mode then unlock immediately.
+	if check_owner_mode(args, inputs)
+	{
+		return Ok(());
+	}
+
+	// Parse the optional features.
+	let options = determine_sale_options(args)?;
+
+	// Check the inputs to ensure there is a single input Token Sale Cell, or several if multiple Cells are enabled.
+	let type_script = validate_token_sale_inputs(&options, lock_script, inputs)?;
+
+	// Check that the purchase is made on or after the sale start.
+	validate_sale_start(&options, lock_script, inputs)?;
+
+	// Check the outputs to ensure there is an output Token Sale Cell for each input Token Sale Cell.
+	validate_token_sale_outputs(lock_script, &type_script, inputs, outputs)?;
+
+	// Find all the capacity, token, and cost amounts.
+	let token_cost = determine_token_cost(args)?;
+	let (input_capacity_amount, input_token_amount) = determine_token_sale_cell_amounts(lock_script, &type_script, inputs)?;
+	let (output_capacity_amount, output_token_amount) = determine_token_sale_cell_amounts(lock_script, &type_script, outputs)?;
+
+	// Check that the buyer is whitelisted and receives the tokens.
+	validate_whitelist(&options, lock_script, &type_script, inputs, outputs, input_token_amount, output_token_amount)?;
+
+	// Validate that all amounts are in balance. Capacity leaving the Token Sale Cell is a buy-back if it is enabled.
+	let price_denominator = options.price_denominator.unwrap_or(1);
+	let overpayment = options.flags & FLAG_OVERPAYMENT != 0;
+	match options.buy_back_cost
+	{
+		Some(buy_back_cost) if output_capacity_amount < input_capacity_amount => validate_buy_back_amounts(buy_back_cost, price_denominator, overpayment, input_capacity_amount, output_capacity_amount, input_token_amount, output_token_amount)?,
+		_ => validate_amounts(token_cost, price_denominator, overpayment, input_capacity_amount, output_capacity_amount, input_token_amount, output_token_amount)?,
+	}
+
+	// Validate that the remaining data of the Token Sale Cell is unchanged.
+	validate_token_sale_data(lock_script, inputs, outputs)?;
+
+	Ok(())
+}
+
+/// Validate every Token Sale script group in a transaction.
+///
+/// The inputs are the resolved input Cells of the transaction. A script group is formed by all input Cells with an
+/// identical Lock Script using the specified Token Sale code hash and hash type, exactly as CKB groups them on-chain.
+pub fn validate_transaction(code_hash: &[u8], hash_type: u8, inputs: &[Cell], outputs: &[Cell]) -> Result<(), Error>
+{
+	for (i, cell) in inputs.iter().enumerate()
+	{
+		// Skip Cells which do not use the Token Sale Lock Script.
+		if cell.lock.code_hash != code_hash || cell.lock.hash_type != hash_type
+		{
+			continue;
+		}
+
+		// Skip script groups which have already been validated.
+		if inputs[..i].iter().any(|previous| previous.lock == cell.lock)
+		{
+			continue;
+		}
+
+		validate(&cell.lock, inputs, outputs)?;
+	}
+
+	Ok(())
+}
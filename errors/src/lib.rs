@@ -0,0 +1,29 @@
+//! Simple Token Sale Error Codes
+//! https://github.com/jordanmack/token-sale
+//!
+//! The error codes returned by the Token Sale Lock Script. These values are part of the public interface of the
+//! script and must remain stable. Tooling and tests should use this enum instead of duplicating the numeric values.
+
+#![no_std]
+
+/// Local error values.
+/// Low values are reserved for Sys Error codes.
+/// Values 100+ are for custom errors.
+#[repr(i8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error
+{
+	IndexOutOfBound = 1,
+	ItemMissing,
+	LengthNotEnough,
+	Encoding,
+	ArgsLen = 100,
+	AmountCkbytes,
+	AmountSudt,
+	ExchangeRate,
+	InvalidCost,
+	InvalidStructure,
+	InvalidFlags,
+	SaleWindow,
+	Whitelist,
+}
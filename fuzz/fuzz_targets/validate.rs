@@ -0,0 +1,131 @@
+//! Feeds arbitrary args and Cells into the shared validation core.
+//!
+//! The target checks that validation never panics, and that any transaction accepted outside of owner mode
+//! actually satisfies the exchange rate or buy-back rate described by the args, including any price denominator and overpayment.
+
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use token_sale_core::{determine_sale_options, validate, Cell, Script, ARGS_LEN, COST_AMOUNT_LEN, FLAG_MULTIPLE_CELLS, FLAG_OVERPAYMENT, LOCK_HASH_LEN, SUDT_AMOUNT_DATA_LEN};
+
+// Constants
+const TOKEN_SALE_CODE_HASH: [u8; 32] = [1u8; 32];
+const OTHER_LOCK_CODE_HASH: [u8; 32] = [2u8; 32];
+const SUDT_CODE_HASH: [u8; 32] = [3u8; 32];
+
+/// An arbitrary Cell.
+#[derive(Arbitrary, Debug)]
+struct FuzzCell
+{
+	capacity: u64,
+	is_token_sale: bool,
+	lock_hash: [u8; 32],
+	type_args: Option<u8>,
+	data: Vec<u8>,
+	since: u64,
+	witness_lock: Vec<u8>,
+}
+
+/// An arbitrary Token Sale script group and transaction.
+#[derive(Arbitrary, Debug)]
+struct FuzzInput
+{
+	args: Vec<u8>,
+	inputs: Vec<FuzzCell>,
+	outputs: Vec<FuzzCell>,
+}
+
+/// Create a view of a fuzzed Cell.
+fn create_cell_view<'a>(cell: &'a FuzzCell, token_sale_lock: &Script<'a>, type_args: &'a [[u8; 1]; 256]) -> Cell<'a>
+{
+	let lock = if cell.is_token_sale { *token_sale_lock } else { Script { code_hash: &OTHER_LOCK_CODE_HASH, hash_type: 0, args: &[] } };
+	let type_ = cell.type_args.map(|i| Script { code_hash: &SUDT_CODE_HASH, hash_type: 0, args: &type_args[i as usize] });
+
+	Cell
+	{
+		capacity: cell.capacity,
+		lock,
+		lock_hash: &cell.lock_hash,
+		type_,
+		data: &cell.data,
+		since: cell.since,
+		witness_lock: &cell.witness_lock,
+	}
+}
+
+/// Read a little endian u128 token amount from Cell data.
+fn token_amount(data: &[u8]) -> u128
+{
+	let mut buf = [0u8; SUDT_AMOUNT_DATA_LEN];
+	buf.copy_from_slice(&data[0..SUDT_AMOUNT_DATA_LEN]);
+	u128::from_le_bytes(buf)
+}
+
+fuzz_target!(|input: FuzzInput|
+{
+	let mut type_args = [[0u8; 1]; 256];
+	for (i, args) in type_args.iter_mut().enumerate()
+	{
+		args[0] = i as u8;
+	}
+
+	let token_sale_lock = Script { code_hash: &TOKEN_SALE_CODE_HASH, hash_type: 0, args: &input.args };
+	let inputs: Vec<Cell> = input.inputs.iter().map(|cell| create_cell_view(cell, &token_sale_lock, &type_args)).collect();
+	let outputs: Vec<Cell> = input.outputs.iter().map(|cell| create_cell_view(cell, &token_sale_lock, &type_args)).collect();
+
+	if validate(&token_sale_lock, &inputs, &outputs).is_err()
+	{
+		return;
+	}
+
+	// Owner mode unlocks unconditionally.
+	if inputs.iter().any(|cell| input.args[0..LOCK_HASH_LEN] == cell.lock_hash[..])
+	{
+		return;
+	}
+
+	// Outside of owner mode, exactly one Token Sale Cell must exist on each side unless multiple Cells are enabled.
+	let options = determine_sale_options(&input.args).expect("options");
+	let group_inputs: Vec<&Cell> = inputs.iter().filter(|cell| cell.lock == token_sale_lock).collect();
+	assert!(group_inputs.len() == 1 || options.flags & FLAG_MULTIPLE_CELLS != 0);
+	let type_script = group_inputs[0].type_.expect("type script");
+	assert!(group_inputs.iter().all(|cell| cell.type_ == Some(type_script)));
+	let group_outputs: Vec<&Cell> = outputs.iter().filter(|cell| cell.lock == token_sale_lock && cell.type_ == Some(type_script)).collect();
+	assert_eq!(group_outputs.len(), group_inputs.len());
+
+	// The exchange must balance across the totals of the Token Sale Cells.
+	let capacity_in: u64 = group_inputs.iter().map(|cell| cell.capacity).sum();
+	let capacity_out: u64 = group_outputs.iter().map(|cell| cell.capacity).sum();
+	let tokens_in: u128 = group_inputs.iter().map(|cell| token_amount(cell.data)).sum();
+	let tokens_out: u128 = group_outputs.iter().map(|cell| token_amount(cell.data)).sum();
+
+	// The costs are per denominator tokens when a price denominator is set.
+	let price_denominator = options.price_denominator.unwrap_or(1) as u128;
+	assert!(price_denominator > 0);
+
+	// The exchange may only favor the Token Sale Cell when overpayment is allowed.
+	let overpayment = options.flags & FLAG_OVERPAYMENT != 0;
+
+	let mut cost = [0u8; COST_AMOUNT_LEN];
+	cost.copy_from_slice(&input.args[LOCK_HASH_LEN..ARGS_LEN]);
+	let cost = u64::from_le_bytes(cost) as u128;
+
+	// Capacity can only leave the Token Sale Cell when tokens are sold back at the buy-back cost, which is never above the cost.
+	if capacity_out < capacity_in
+	{
+		let buy_back_cost = options.buy_back_cost.expect("buy-back enabled") as u128;
+		let capacity_paid = (capacity_in - capacity_out) as u128 * price_denominator;
+		let tokens_returned = tokens_out.checked_sub(tokens_in).expect("tokens increased");
+		assert!(buy_back_cost > 0 && buy_back_cost <= cost && tokens_returned > 0);
+		let capacity_owed = tokens_returned.checked_mul(buy_back_cost).expect("capacity owed");
+		assert!(capacity_paid == capacity_owed || (overpayment && capacity_paid < capacity_owed));
+		return;
+	}
+
+	let capacity_received = capacity_out.checked_sub(capacity_in).expect("capacity increased") as u128 * price_denominator;
+	let tokens_sold = tokens_in.checked_sub(tokens_out).expect("tokens decreased");
+	assert!(cost > 0 && capacity_received > 0 && tokens_sold > 0);
+	let capacity_required = tokens_sold.checked_mul(cost).expect("capacity required");
+	assert!(capacity_received == capacity_required || (overpayment && capacity_received > capacity_required));
+});
@@ -1,20 +1,57 @@
 use super::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use ckb_system_scripts::BUNDLED_CELL;
 use ckb_testtool::{builtin::ALWAYS_SUCCESS, context::Context};
+use ckb_tool::ckb_crypto::secp::{Generator, Privkey};
+use ckb_tool::ckb_hash::{blake2b_256, new_blake2b};
+use ckb_tool::ckb_types::H256;
 use ckb_tool::{ckb_error::assert_error_eq, ckb_script::ScriptError};
 use ckb_tool::ckb_types::{bytes::Bytes, packed::*, prelude::*};
-use ckb_tool::ckb_types::core::{Capacity, TransactionBuilder};
+use ckb_tool::ckb_types::core::{Capacity, EpochNumberWithFraction, HeaderBuilder, HeaderView, ScriptHashType, TransactionBuilder, TransactionView};
+use token_sale_errors::Error;
+
+mod adversarial;
+mod migration;
+mod owner_locks;
 
 // Constants
 const MAX_CYCLES: u64 = 10_000_000;
-
-// Error Codes
-const ERROR_ARGS_LEN: i8 = 100;
-const ERROR_AMOUNT_CKBYTES: i8 = 101;
-const ERROR_AMOUNT_SUDT: i8 = 102;
-const ERROR_EXCHANGE_RATE: i8 = 103;
-const ERROR_COST: i8 = 104;
-const ERROR_STRUCTURE: i8 = 105;
+const DEBUG_MAX_CYCLES: u64 = 100_000_000; // Unoptimized debug builds consume several times the cycles of a release build.
+const CYCLES_FILE: &str = "cycles.txt"; // The file containing the cycle baselines, relative to the tests crate.
+const CYCLES_TOLERANCE_ENV_VAR: &str = "CYCLES_TOLERANCE"; // Overrides the allowed cycle regression percentage.
+const CYCLES_UPDATE_ENV_VAR: &str = "CYCLES_UPDATE"; // Rewrites the cycle baselines with the measured values when set.
+const DEFAULT_CYCLES_TOLERANCE: u64 = 5; // The allowed cycle regression percentage.
+const TOKEN_SCRIPTS_DIR: &str = "deps"; // The directory containing deployed token script binaries, relative to the tests crate.
+const CORE_TOKEN_SALE_CODE_HASH: [u8; 32] = [1u8; 32]; // Token Sale code hash used when testing the validation core directly.
+const CORE_SUDT_CODE_HASH: [u8; 32] = [2u8; 32]; // SUDT code hash used when testing the validation core directly.
+const CORE_OWNER_LOCK_HASH: [u8; 32] = [3u8; 32]; // Owner lock hash used when testing the validation core directly.
+const CORE_OTHER_LOCK_HASH: [u8; 32] = [4u8; 32]; // Non-owner lock hash used when testing the validation core directly.
+const FIXTURES_DIR: &str = "fixtures"; // The directory containing the golden transaction fixtures, relative to the tests crate.
+const FIXTURES_UPDATE_ENV_VAR: &str = "FIXTURES_UPDATE"; // Rewrites the golden transaction fixtures when set.
+const FIXTURES_KEY_SEED: u64 = 3; // The seed used to generate the key which signs the golden transaction fixtures.
+const SIGNATURE_LEN: usize = 65; // Number of bytes for a recoverable secp256k1 signature.
+const MAX_TRANSACTION_BYTES: u64 = 597_000; // The maximum serialized size of a block, which no transaction can exceed. (MAX_BLOCK_BYTES in the CKB consensus)
+const SCENARIO_SEED_ENV_VAR: &str = "SCENARIO_SEED"; // Replays the sale lifecycle of a single seed when set.
+const DEFAULT_SCENARIO_SEEDS: u64 = 8; // The number of seeded sale lifecycles to run by default.
+const SINCE_RELATIVE_FLAG: u64 = 1 << 63; // Flag for a since value relative to the block of the input Cell.
+const SINCE_METRIC_BLOCK_NUMBER: u64 = 0; // Metric flag for a since value in blocks.
+const SINCE_METRIC_EPOCH: u64 = 1 << 61; // Metric flag for a since value in epochs.
+const SINCE_METRIC_TIMESTAMP: u64 = 2 << 61; // Metric flag for a since value in seconds of median time.
+const ANYONE_CAN_PAY_FILE: &str = "anyone_can_pay"; // The file name of the ACP binary in the deps directory.
+const DEPLOYED_TOKEN_SCRIPTS: [(&str, &str); 2] = [("simple-udt", "simple_udt"), ("xudt", "xudt_rce")]; // Registry names and file names.
+
+/// The cycle limit for verifying transactions in the current test environment.
+fn max_cycles() -> u64
+{
+	match env::var(TEST_ENV_VAR).unwrap_or_else(|_| "debug".to_owned()).to_lowercase().as_str()
+	{
+		"release" => MAX_CYCLES,
+		_ => DEBUG_MAX_CYCLES,
+	}
+}
 
 /// A structure for holding common resources used in multiple tests.
 struct LocalResources
@@ -23,6 +60,7 @@ struct LocalResources
 	out_points: HashMap<String, OutPoint>,
 	scripts: HashMap<String, Script>,
 	deps: HashMap<String, CellDep>,
+	token_scripts: HashMap<String, Bytes>,
 }
 
 impl LocalResources
@@ -35,11 +73,52 @@ impl LocalResources
 			out_points: HashMap::new(),
 			scripts: HashMap::new(),
 			deps: HashMap::new(),
+			token_scripts: HashMap::new(),
+		}
+	}
+}
+
+/// Load the registry of token script binaries which the Token Sale Lock Script is tested against.
+/// The bundled SUDT is always available. Deployed builds are loaded from the deps directory when present.
+fn load_token_scripts() -> HashMap<String, Bytes>
+{
+	let mut token_scripts = HashMap::new();
+	token_scripts.insert("sudt".to_owned(), Loader::default().load_binary("sudt"));
+
+	for (name, file_name) in DEPLOYED_TOKEN_SCRIPTS.iter()
+	{
+		let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(TOKEN_SCRIPTS_DIR).join(file_name);
+		if let Ok(binary) = fs::read(path)
+		{
+			token_scripts.insert((*name).to_owned(), binary.into());
 		}
 	}
+
+	token_scripts
+}
+
+/// Load a binary from the deps directory, failing if it is not present.
+fn load_deps_binary(file_name: &str) -> Bytes
+{
+	let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(TOKEN_SCRIPTS_DIR).join(file_name);
+	fs::read(&path).unwrap_or_else(|err| panic!("The {} binary was not found at {}: {}.", file_name, path.display(), err)).into()
+}
+
+/// Require a deployed token script binary to be present in the deps directory, so that the tests which depend on it fail
+/// instead of silently passing without it.
+fn require_token_script(name: &str)
+{
+	let (_, file_name) = DEPLOYED_TOKEN_SCRIPTS.iter().find(|(deployed_name, _)| *deployed_name == name).expect("deployed token script");
+	assert!(load_token_scripts().contains_key(name), "The {} binary was not found. Place it in {}/{}.", name, TOKEN_SCRIPTS_DIR, file_name);
 }
 
 fn build_default_context_and_resources() -> (Context, TransactionBuilder, LocalResources)
+{
+	build_context_and_resources("sudt")
+}
+
+/// Build the context and resources using the specified token script from the registry in place of the bundled SUDT.
+fn build_context_and_resources(token_script: &str) -> (Context, TransactionBuilder, LocalResources)
 {
 	// Create the default context.
 	let mut context = Context::default();
@@ -48,8 +127,10 @@ fn build_default_context_and_resources() -> (Context, TransactionBuilder, LocalR
 	let mut resources = LocalResources::new();
 
 	// Load Binaries.
+	resources.token_scripts = load_token_scripts();
+	let token_script_binary = resources.token_scripts.get(token_script).unwrap_or_else(|| panic!("Token script not found: {}", token_script)).clone();
 	resources.binaries.insert("token-sale".to_owned(), Loader::default().load_binary("token-sale"));
-	resources.binaries.insert("sudt".to_owned(), Loader::default().load_binary("sudt"));
+	resources.binaries.insert("sudt".to_owned(), token_script_binary);
 	
 	// Deploy Binaries.
 	resources.out_points.insert("token-sale".to_owned(), context.deploy_contract(resources.binaries.get("token-sale").unwrap().clone()));
@@ -122,6 +203,13 @@ fn create_output_token_sale_cell(context: &mut Context, resources: &LocalResourc
 	lock_hash_cost_id.append(&mut cost.to_le_bytes().to_vec());
 	lock_hash_cost_id.append(&mut id.to_le_bytes().to_vec());
 	let token_sale_script_args: Bytes = lock_hash_cost_id.into();
+
+	create_output_token_sale_cell_with_args(context, resources, capacity, tokens, token_sale_script_args, &lock_hash_sudt)
+}
+
+/// Create an output Token Sale Cell with the specified Token Sale Lock args on an SUDT token.
+fn create_output_token_sale_cell_with_args(context: &mut Context, resources: &LocalResources, capacity: u64, tokens: u128, token_sale_script_args: Bytes, lock_hash_sudt: &[u8; 32]) -> (CellOutput, Bytes)
+{
 	let token_sale_script = context.build_script(resources.out_points.get("token-sale").unwrap(), token_sale_script_args).expect("script");
 
 	let sudt_script_args: Bytes = lock_hash_sudt.to_vec().into();
@@ -167,903 +255,2965 @@ fn create_output_sudt_cell(context: &mut Context, resources: &LocalResources, ca
 	(output, output_data)
 }
 
-#[test]
-fn test_no_change()
+/// Create xUDT Cell data consisting of the token amount followed by the extension data.
+fn create_xudt_data(tokens: u128, extension: &[u8]) -> Bytes
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
+	let mut data = tokens.to_le_bytes().to_vec();
+	data.extend_from_slice(extension);
 
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
+	data.into()
+}
 
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 1_000, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 1_000, 1_000, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+/// Create xUDT args consisting of the owner lock hash, the flags, and the extension args.
+fn create_xudt_args(owner_lock_hash: &[u8; 32], flags: u32, extension_args: &[u8]) -> Vec<u8>
+{
+	let mut args = owner_lock_hash.to_vec();
+	args.append(&mut flags.to_le_bytes().to_vec());
+	args.extend_from_slice(extension_args);
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_AMOUNT_CKBYTES));
+	args
 }
 
-#[test]
-fn test_buy()
+/// Create an output xUDT Cell with extension data. The context must be built with the xUDT token script.
+fn create_output_xudt_cell(context: &mut Context, resources: &LocalResources, capacity: u64, tokens: u128, extension: &[u8], is_owner_mode: bool) -> (CellOutput, Bytes)
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 1_000);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 800);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 1_100, 99, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_sudt_cell(&mut context, &resources, 100, 1, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	let (output, _) = create_output_sudt_cell(context, resources, capacity, tokens, is_owner_mode);
+	let output_data = create_xudt_data(tokens, extension);
 
-	// Execute the transaction.
-	let _cycles = context.verify_tx(&tx, MAX_CYCLES).expect("pass verification");
-	// println!("Cycles: {}", cycles);
+	(output, output_data)
 }
 
-#[test]
-fn test_add_lock()
+/// Create an output Token Sale Cell consisting of a Token Sale Lock on an xUDT token with extension data. The context must be built with the xUDT token script.
+fn create_output_token_sale_xudt_cell(context: &mut Context, resources: &LocalResources, capacity: u64, tokens: u128, extension: &[u8], cost: u64, id: u32, token_sale_owner_mode: bool, xudt_owner_mode: bool) -> (CellOutput, Bytes)
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-	let input = create_input_sudt_cell(&mut context, &resources, 100, 100, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 100);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 100, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	let (output, _) = create_output_token_sale_cell(context, resources, capacity, tokens, cost, id, token_sale_owner_mode, xudt_owner_mode);
+	let output_data = create_xudt_data(tokens, extension);
 
-	// Execute the transaction.
-	let _cycles = context.verify_tx(&tx, MAX_CYCLES).expect("pass verification");
-	// println!("Cycles: {}", cycles);
+	(output, output_data)
 }
 
-#[test]
-fn test_remove_lock()
+/// Create a view of a Script for the shared validation core.
+fn create_script_view<'a>(script: ScriptReader<'a>) -> token_sale_core::Script<'a>
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = true;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 1_000);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_sudt_cell(&mut context, &resources, 100, 100, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
-
-	// Execute the transaction.
-	let _cycles = context.verify_tx(&tx, MAX_CYCLES).expect("pass verification");
-	// println!("Cycles: {}", cycles);
+	token_sale_core::Script
+	{
+		code_hash: script.code_hash().raw_data(),
+		hash_type: script.hash_type().as_slice()[0],
+		args: script.args().raw_data(),
+	}
 }
 
-#[test]
-fn test_remove_lock_no_owner()
+/// Create a view of a Cell for the shared validation core.
+fn create_cell_view<'a>(cell: &'a CellOutput, data: &'a Bytes, lock_hash: &'a [u8; 32], since: u64, witness_lock: &'a [u8]) -> token_sale_core::Cell<'a>
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 1_000);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_sudt_cell(&mut context, &resources, 100, 100, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	let cell = cell.as_reader();
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_STRUCTURE));
+	token_sale_core::Cell
+	{
+		capacity: cell.capacity().unpack(),
+		lock: create_script_view(cell.lock()),
+		lock_hash: &lock_hash[..],
+		type_: cell.type_().to_opt().map(create_script_view),
+		data: &data[..],
+		since,
+		witness_lock,
+	}
 }
 
-#[test]
-fn test_split_lock()
+/// Load the lock field of the WitnessArgs at the specified index. Empty when absent or malformed.
+fn load_witness_lock(tx: &TransactionView, index: usize) -> Bytes
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = true;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 100, 50, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 100, 50, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	tx.witnesses().get(index)
+		.and_then(|witness| WitnessArgs::from_slice(&witness.raw_data()).ok())
+		.and_then(|witness_args| witness_args.lock().to_opt())
+		.map(|lock| lock.raw_data())
+		.unwrap_or_default()
+}
 
-	// Execute the transaction.
-	let _cycles = context.verify_tx(&tx, MAX_CYCLES).expect("pass verification");
-	// println!("Cycles: {}", cycles);
+/// Validate a transaction on the host using the shared validation core.
+fn validate_transaction_host(context: &Context, resources: &LocalResources, tx: &TransactionView) -> Result<(), Error>
+{
+	// Resolve the input Cells and collect the output Cells.
+	let inputs: Vec<(CellOutput, Bytes)> = tx.inputs().into_iter().map(|input| context.get_cell(&input.previous_output()).expect("cell")).collect();
+	let outputs: Vec<(CellOutput, Bytes)> = tx.outputs_with_data_iter().collect();
+	let input_lock_hashes: Vec<[u8; 32]> = inputs.iter().map(|(cell, _)| cell.lock().calc_script_hash().unpack()).collect();
+	let input_since: Vec<u64> = tx.inputs().into_iter().map(|input| input.since().unpack()).collect();
+	let input_witness_locks: Vec<Bytes> = (0..inputs.len()).map(|i| load_witness_lock(tx, i)).collect();
+	let output_lock_hashes: Vec<[u8; 32]> = outputs.iter().map(|(cell, _)| cell.lock().calc_script_hash().unpack()).collect();
+
+	// Create views of the Cells.
+	let input_views: Vec<_> = inputs.iter().zip(input_lock_hashes.iter()).zip(input_since.iter()).zip(input_witness_locks.iter())
+		.map(|((((cell, data), lock_hash), since), witness_lock)| create_cell_view(cell, data, lock_hash, *since, witness_lock))
+		.collect();
+	let output_views: Vec<_> = outputs.iter().zip(output_lock_hashes.iter()).map(|((cell, data), lock_hash)| create_cell_view(cell, data, lock_hash, 0, &[])).collect();
+
+	// The Token Sale Lock Script is referenced by data hash.
+	let code_hash = CellOutput::calc_data_hash(resources.binaries.get("token-sale").unwrap());
+
+	token_sale_core::validate_transaction(code_hash.as_slice(), ScriptHashType::Data.into(), &input_views, &output_views)
 }
 
-#[test]
-fn test_split_lock_no_owner()
+
+/// Load the cycle baselines, keyed by test environment and transaction name.
+fn load_cycle_baselines(path: &PathBuf, update: bool) -> BTreeMap<String, u64>
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
+	let mut baselines = BTreeMap::new();
 
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
+	// A missing file is only acceptable when the baselines are being regenerated.
+	let contents = match fs::read_to_string(path)
+	{
+		Ok(contents) => contents,
+		Err(_) if update => return baselines,
+		Err(err) => panic!("Unable to read the cycle baselines from {}: {}. Run with {}=1 to generate them.", path.display(), err, CYCLES_UPDATE_ENV_VAR),
+	};
 
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 500, 50, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 500, 50, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#'))
+	{
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		assert_eq!(fields.len(), 3, "Invalid cycle baseline: {}", line);
+		baselines.insert(format!("{} {}", fields[0], fields[1]), fields[2].parse().expect("cycles"));
+	}
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_STRUCTURE));
+	baselines
 }
 
-#[test]
-fn test_combine_lock()
+/// Save the cycle baselines.
+fn save_cycle_baselines(path: &PathBuf, baselines: &BTreeMap<String, u64>)
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = true;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 50, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 50, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 300, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	let mut contents = String::from("# Cycles consumed by canonical transactions. Format: <test env> <transaction> <cycles>\n");
+	contents.push_str(&format!("# Regenerate with: {}=1 capsule test\n", CYCLES_UPDATE_ENV_VAR));
+	for (key, cycles) in baselines
+	{
+		contents.push_str(&format!("{} {}\n", key, cycles));
+	}
 
-	// Execute the transaction.
-	let _cycles = context.verify_tx(&tx, MAX_CYCLES).expect("pass verification");
-	// println!("Cycles: {}", cycles);
+	fs::write(path, contents).expect("write cycle baselines");
 }
 
-#[test]
-fn test_combine_lock_no_owner()
+/// Create Token Sale Lock args for testing the shared validation core directly.
+fn create_core_args(owner_lock_hash: &[u8; 32], cost: u64) -> Vec<u8>
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 50, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 50, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 300, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	let mut args = owner_lock_hash.to_vec();
+	args.append(&mut cost.to_le_bytes().to_vec());
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_STRUCTURE));
+	args
 }
 
-#[test]
-fn test_buy_invalid_ckbytes()
+/// Create Token Sale Lock args with a unique identifier, the flags, and the fields of the flags.
+fn create_flagged_args(owner_lock_hash: &[u8; 32], cost: u64, id: u32, flags: u8, fields: &[u8]) -> Vec<u8>
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 200);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 900, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	let mut args = create_core_args(owner_lock_hash, cost);
+	args.append(&mut id.to_le_bytes().to_vec());
+	args.push(flags);
+	args.extend_from_slice(fields);
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_AMOUNT_CKBYTES));
+	args
 }
 
-#[test]
-fn test_buy_invalid_sudt()
+/// Calculate the lock hash of the capacity and SUDT Cells in a Scenario, which enables owner mode.
+fn create_scenario_owner_lock_hash() -> [u8; 32]
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-	let input = create_input_sudt_cell(&mut context, &resources, 100, 100, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 1_200, 200, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	let lock_script = Script::new_builder()
+		.code_hash(CellOutput::calc_data_hash(&ALWAYS_SUCCESS))
+		.hash_type(ScriptHashType::Data.into())
+		.args(Bytes::from(vec!(0u8, 1)).pack())
+		.build();
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_AMOUNT_SUDT));
+	lock_script.calc_script_hash().unpack()
 }
 
-#[test]
-fn test_sell()
+/// Calculate the first 20 bytes of the Blake2b hash of the data.
+fn blake160(data: &[u8]) -> [u8; 20]
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_sudt_cell(&mut context, &resources, 100, 1, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 900, 101, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 200);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	let mut buf = [0u8; 20];
+	buf.copy_from_slice(&blake2b_256(data)[0..20]);
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_AMOUNT_CKBYTES));
+	buf
 }
 
-#[test]
-fn test_change_cost()
+/// Load a binary bundled with ckb-system-scripts.
+fn load_system_script(name: &str) -> Bytes
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = true;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 1_000, 100, 50, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 100);
-	outputs.push(output);
-	outputs_data.push(output_data);
+	BUNDLED_CELL.get(&format!("specs/cells/{}", name)).expect("system script").to_vec().into()
+}
 
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+/// Create an ACP Lock Script owned by a key generated from the seed.
+fn create_anyone_can_pay_lock(binary: &Bytes, seed: u64) -> Script
+{
+	let key = Generator::non_crypto_safe_prng(seed).gen_privkey();
 
-	// Execute the transaction.
-	let _cycles = context.verify_tx(&tx, MAX_CYCLES).expect("pass verification");
-	// println!("Cycles: {}", cycles);
+	Script::new_builder()
+		.code_hash(CellOutput::calc_data_hash(binary))
+		.hash_type(ScriptHashType::Data.into())
+		.args(Bytes::from(blake160(&key.pubkey().expect("pubkey").serialize()).to_vec()).pack())
+		.build()
 }
 
-#[test]
-fn test_change_cost_no_owner()
+/// Deploy the ACP lock and create a Lock Script using it, owned by a key generated from the seed.
+/// Returns the Lock Script and the cell deps it requires.
+fn deploy_anyone_can_pay_lock(context: &mut Context, seed: u64) -> (Script, Vec<CellDep>)
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 1_000, 100, 50, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 100);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	let binary = load_deps_binary(ANYONE_CAN_PAY_FILE);
+	let lock = create_anyone_can_pay_lock(&binary, seed);
+	let acp_dep = CellDep::new_builder().out_point(context.deploy_cell(binary)).build();
+	let secp256k1_data_dep = CellDep::new_builder().out_point(context.deploy_cell(load_system_script("secp256k1_data"))).build();
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_STRUCTURE));
+	(lock, vec!(acp_dep, secp256k1_data_dep))
 }
 
-#[test]
-fn test_remove_capacity()
+/// Create a Merkle root of the leaves and the proof of the leaf at the specified index.
+/// Each pair of nodes is hashed in sorted order, and an unpaired node is carried up to the next level.
+fn create_merkle_proof(leaves: &[[u8; 32]], index: usize) -> ([u8; 32], Vec<u8>)
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = true;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
+	let mut level = leaves.to_vec();
+	let mut index = index;
+	let mut proof = vec!();
+	while level.len() > 1
+	{
+		if let Some(sibling) = level.get(index ^ 1)
+		{
+			proof.extend_from_slice(sibling);
+		}
+		level = level.chunks(2).map(|pair| if pair.len() == 2 { hash_merkle_pair(&pair[0], &pair[1]) } else { pair[0] }).collect();
+		index /= 2;
+	}
 
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
+	(level[0], proof)
+}
 
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 100, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 1_000);
-	outputs.push(output);
-	outputs_data.push(output_data);
+/// Hash a pair of Merkle nodes in sorted order.
+fn hash_merkle_pair(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32]
+{
+	let (left, right) = if a <= b { (a, b) } else { (b, a) };
 
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	blake2b_256([&left[..], &right[..]].concat())
+}
 
-	// Execute the transaction.
-	let _cycles = context.verify_tx(&tx, MAX_CYCLES).expect("pass verification");
-	// println!("Cycles: {}", cycles);
+/// Create a view of a Script for testing the shared validation core directly.
+fn create_core_script<'a>(code_hash: &'a [u8], args: &'a [u8]) -> token_sale_core::Script<'a>
+{
+	token_sale_core::Script
+	{
+		code_hash,
+		hash_type: ScriptHashType::Data.into(),
+		args,
+	}
 }
 
-#[test]
-fn test_remove_capacity_no_owner()
+/// Create a view of a Cell for testing the shared validation core directly. The since value and witness lock are empty.
+fn create_core_cell<'a>(capacity: u64, lock: token_sale_core::Script<'a>, lock_hash: &'a [u8], type_: Option<token_sale_core::Script<'a>>, data: &'a [u8]) -> token_sale_core::Cell<'a>
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
+	token_sale_core::Cell
+	{
+		capacity,
+		lock,
+		lock_hash,
+		type_,
+		data,
+		since: 0,
+		witness_lock: &[],
+	}
+}
 
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
+/// Create a block header with the specified block number, epoch, and timestamp in milliseconds.
+fn create_header(number: u64, epoch: EpochNumberWithFraction, timestamp: u64) -> HeaderView
+{
+	HeaderBuilder::default()
+		.number(number.pack())
+		.epoch(epoch.full_value().pack())
+		.timestamp(timestamp.pack())
+		.build()
+}
 
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_token_sale_cell(&mut context, &resources, 1_000, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
+/// Advance an epoch by the specified number of blocks, assuming all epochs have the same length.
+fn advance_epoch(epoch: EpochNumberWithFraction, blocks: u64) -> EpochNumberWithFraction
+{
+	let index = epoch.index() + blocks;
 
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 100, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 1_000);
-	outputs.push(output);
-	outputs_data.push(output_data);
+	EpochNumberWithFraction::new(epoch.number() + index / epoch.length(), index % epoch.length(), epoch.length())
+}
 
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+/// Create a since value in blocks.
+fn create_since_block_number(number: u64, relative: bool) -> u64
+{
+	(if relative { SINCE_RELATIVE_FLAG } else { 0 }) | SINCE_METRIC_BLOCK_NUMBER | number
+}
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_AMOUNT_CKBYTES));
+/// Create a since value in epochs.
+fn create_since_epoch(epoch: EpochNumberWithFraction, relative: bool) -> u64
+{
+	(if relative { SINCE_RELATIVE_FLAG } else { 0 }) | SINCE_METRIC_EPOCH | epoch.full_value()
 }
 
-#[test]
-fn test_remove_tokens()
+/// Create a since value in seconds of median time.
+fn create_since_timestamp(timestamp: u64, relative: bool) -> u64
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = true;
-	const SUDT_OWNER_MODE: bool = false;
+	(if relative { SINCE_RELATIVE_FLAG } else { 0 }) | SINCE_METRIC_TIMESTAMP | timestamp
+}
 
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
+/// A small deterministic pseudorandom number generator for reproducible randomized tests.
+struct XorShift
+{
+	state: u64,
+}
 
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 1_100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
+impl XorShift
+{
+	pub fn new(seed: u64) -> Self
+	{
+		// The state of a xorshift generator must never be zero.
+		Self { state: seed ^ 0x9E37_79B9_7F4A_7C15 }
+	}
 
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 100, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_sudt_cell(&mut context, &resources, 100, 1_000, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
+	/// Generate the next value.
+	pub fn next_u64(&mut self) -> u64
+	{
+		self.state ^= self.state << 13;
+		self.state ^= self.state >> 7;
+		self.state ^= self.state << 17;
+		self.state
+	}
 
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	/// Generate a value in the inclusive range.
+	pub fn range(&mut self, min: u64, max: u64) -> u64
+	{
+		min + self.next_u64() % (max - min + 1)
+	}
 
-	// Execute the transaction.
-	let _cycles = context.verify_tx(&tx, MAX_CYCLES).expect("pass verification");
-	// println!("Cycles: {}", cycles);
+	/// Shuffle the items in place.
+	pub fn shuffle<T>(&mut self, items: &mut Vec<T>)
+	{
+		for i in (1..items.len()).rev()
+		{
+			let j = self.range(0, i as u64) as usize;
+			items.swap(i, j);
+		}
+	}
 }
 
-#[test]
-fn test_remove_tokens_no_owner()
+/// Set the token Type Script args and append the extension data to the token amount of a token Cell. Cells without a
+/// Type Script are unchanged.
+fn set_token_options(output: CellOutput, output_data: Bytes, type_args: &Option<Bytes>, extension: &Bytes) -> (CellOutput, Bytes)
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
+	if output.type_().is_none()
+	{
+		return (output, output_data);
+	}
 
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
+	let output = match type_args
+	{
+		Some(type_args) => set_type_args(output, type_args),
+		None => output,
+	};
+	let mut output_data = output_data.to_vec();
+	output_data.extend_from_slice(extension);
 
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 1_100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_capacity_cell(&mut context, &resources, 200);
-	inputs.push(input);
+	(output, output_data.into())
+}
 
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 200, 100, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_sudt_cell(&mut context, &resources, 100, 1_000, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
+/// A Cell to be created when a Scenario is built.
+enum ScenarioCell
+{
+	Capacity(u64),
+	TokenSale(u64, u128, u64, u32),
+	TokenSaleArgs(u64, u128, Bytes),
+	Sudt(u64, u128),
+}
 
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+/// An input Cell to be created when a Scenario is built, with its since value, the block which created it, and the
+/// lock field of its witness.
+struct ScenarioInput
+{
+	cell: ScenarioCell,
+	since: u64,
+	block: Option<HeaderView>,
+	witness_lock: Option<Bytes>,
+}
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_EXCHANGE_RATE));
+/// A builder for a test transaction and its expected result.
+///
+/// Cells are created when the Scenario is built, so owner modes apply to every Cell regardless of call order.
+struct Scenario
+{
+	token_script: String,
+	token_type_args: Option<Bytes>,
+	token_extension_data: Bytes,
+	token_sale_owner_mode: bool,
+	sudt_owner_mode: bool,
+	inputs: Vec<ScenarioInput>,
+	outputs: Vec<ScenarioCell>,
+	header_deps: Vec<HeaderView>,
 }
 
-#[test]
-fn test_add_tokens()
+impl Scenario
 {
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = true;
-	const SUDT_OWNER_MODE: bool = false;
+	pub fn new() -> Self
+	{
+		Self
+		{
+			token_script: "sudt".to_owned(),
+			token_type_args: None,
+			token_extension_data: Bytes::new(),
+			token_sale_owner_mode: false,
+			sudt_owner_mode: false,
+			inputs: vec!(),
+			outputs: vec!(),
+			header_deps: vec!(),
+		}
+	}
 
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
+	/// Add an input Cell.
+	fn push_input(&mut self, cell: ScenarioCell)
+	{
+		self.inputs.push(ScenarioInput { cell, since: 0, block: None, witness_lock: None });
+	}
 
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 0, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_sudt_cell(&mut context, &resources, 100, 1_100, SUDT_OWNER_MODE);
-	inputs.push(input);
+	/// Set the since value of the most recently added input Cell.
+	/// The context does not enforce since values against headers. Scripts can only observe them.
+	pub fn since(mut self, since: u64) -> Self
+	{
+		self.inputs.last_mut().expect("input").since = since;
+		self
+	}
 
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 100, 1_000, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_sudt_cell(&mut context, &resources, 100, 100, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
+	/// Set the lock field of the WitnessArgs of the most recently added input Cell.
+	pub fn witness_lock(mut self, witness_lock: Bytes) -> Self
+	{
+		self.inputs.last_mut().expect("input").witness_lock = Some(witness_lock);
+		self
+	}
 
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	/// Set the block which created the most recently added input Cell. The header is added as a header dep.
+	pub fn in_block(mut self, header: HeaderView) -> Self
+	{
+		self.inputs.last_mut().expect("input").block = Some(header.clone());
+		self.header_dep(header)
+	}
 
-	// Execute the transaction.
-	let _cycles = context.verify_tx(&tx, MAX_CYCLES).expect("pass verification");
-	// println!("Cycles: {}", cycles);
-}
+	/// Add a header dep.
+	pub fn header_dep(mut self, header: HeaderView) -> Self
+	{
+		if !self.header_deps.iter().any(|header_dep| header_dep.hash() == header.hash())
+		{
+			self.header_deps.push(header);
+		}
+		self
+	}
 
-#[test]
-fn test_add_tokens_no_owner()
-{
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
+	/// Use the specified token script from the registry in place of the bundled SUDT.
+	pub fn token_script(mut self, token_script: &str) -> Self
+	{
+		self.token_script = token_script.to_owned();
+		self
+	}
 
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
+	/// Set the args of the token Type Script of every token Cell, such as xUDT flags and extension args. This replaces
+	/// the owner lock hash set by the SUDT owner mode.
+	pub fn token_type_args(mut self, args: Bytes) -> Self
+	{
+		self.token_type_args = Some(args);
+		self
+	}
 
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 0, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_sudt_cell(&mut context, &resources, 110, 1_100, SUDT_OWNER_MODE);
-	inputs.push(input);
+	/// Append the extension data to the token amount in the data of every token Cell, such as xUDT data.
+	pub fn token_extension_data(mut self, extension: Bytes) -> Self
+	{
+		self.token_extension_data = extension;
+		self
+	}
 
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 110, 1_000, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_sudt_cell(&mut context, &resources, 100, 100, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
+	/// Set the owner lock hash in the Token Sale Lock args to the lock hash of the capacity and SUDT Cells.
+	pub fn token_sale_owner_mode(mut self) -> Self
+	{
+		self.token_sale_owner_mode = true;
+		self
+	}
 
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	/// Set the owner lock hash in the SUDT args to the lock hash of the capacity and SUDT Cells.
+	pub fn sudt_owner_mode(mut self) -> Self
+	{
+		self.sudt_owner_mode = true;
+		self
+	}
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_AMOUNT_SUDT));
-}
+	/// Add an input Cell with capacity.
+	pub fn capacity_in(mut self, capacity: u64) -> Self
+	{
+		self.push_input(ScenarioCell::Capacity(capacity));
+		self
+	}
 
-#[test]
-fn test_add_tokens_dual_owner()
-{
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = true;
-	const SUDT_OWNER_MODE: bool = true;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 0, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 100, 1_000, 100, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 100);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	/// Add an output Cell with capacity.
+	pub fn capacity_out(mut self, capacity: u64) -> Self
+	{
+		self.outputs.push(ScenarioCell::Capacity(capacity));
+		self
+	}
 
-	// Execute the transaction.
-	let _cycles = context.verify_tx(&tx, MAX_CYCLES).expect("pass verification");
-	// println!("Cycles: {}", cycles);
-}
+	/// Add an input Token Sale Cell.
+	pub fn sale_in(mut self, capacity: u64, tokens: u128, cost: u64, id: u32) -> Self
+	{
+		self.push_input(ScenarioCell::TokenSale(capacity, tokens, cost, id));
+		self
+	}
 
-#[test]
-fn test_invalid_args()
-{
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
+	/// Add an output Token Sale Cell.
+	pub fn sale_out(mut self, capacity: u64, tokens: u128, cost: u64, id: u32) -> Self
+	{
+		self.outputs.push(ScenarioCell::TokenSale(capacity, tokens, cost, id));
+		self
+	}
 
-	// Make an Token Sale Cell with an invalid argument.
-	let lock_hash = [0u8; 32].to_vec();
-	let token_sale_script = context.build_script(resources.out_points.get("token-sale").unwrap(), lock_hash.clone().into()).expect("script");
-	let sudt_script = context.build_script(resources.out_points.get("sudt").unwrap(), lock_hash.into()).expect("script");
-	let output = CellOutput::new_builder().capacity(Capacity::shannons(1_000).as_u64().pack()).lock(token_sale_script).type_(Some(sudt_script).pack()).build();
-	let output_data: Bytes = 1_000u128.to_le_bytes().to_vec().into();
-	let input_out_point = context.create_cell(output.clone(), output_data.clone());
-	let input = CellInput::new_builder().previous_output(input_out_point).build();
+	/// Add an input Token Sale Cell with raw Token Sale Lock args.
+	pub fn sale_args_in(mut self, capacity: u64, tokens: u128, args: Bytes) -> Self
+	{
+		self.push_input(ScenarioCell::TokenSaleArgs(capacity, tokens, args));
+		self
+	}
 
-	// Prepare inputs.
-	let mut inputs = vec!();
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	outputs.push(output);
-	outputs_data.push(output_data);
+	/// Add an output Token Sale Cell with raw Token Sale Lock args.
+	pub fn sale_args_out(mut self, capacity: u64, tokens: u128, args: Bytes) -> Self
+	{
+		self.outputs.push(ScenarioCell::TokenSaleArgs(capacity, tokens, args));
+		self
+	}
 
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	/// Add an input SUDT Cell.
+	pub fn sudt_in(mut self, capacity: u64, tokens: u128) -> Self
+	{
+		self.push_input(ScenarioCell::Sudt(capacity, tokens));
+		self
+	}
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_ARGS_LEN));
-}
+	/// Add an output SUDT Cell.
+	pub fn sudt_out(mut self, capacity: u64, tokens: u128) -> Self
+	{
+		self.outputs.push(ScenarioCell::Sudt(capacity, tokens));
+		self
+	}
 
-#[test]
-fn test_invalid_cost()
-{
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
-
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 100);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 100, 0, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_capacity_cell(&mut context, &resources, 100);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 100, 100, 0, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+	/// Shuffle the order of the input Cells and output Cells.
+	pub fn shuffle(mut self, rng: &mut XorShift) -> Self
+	{
+		rng.shuffle(&mut self.inputs);
+		rng.shuffle(&mut self.outputs);
+		self
+	}
 
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_COST));
-}
+	/// Create the Cells, then populate, build, and complete the transaction.
+	pub fn build(self) -> (Context, LocalResources, TransactionView)
+	{
+		// Get defaults.
+		let (mut context, mut tx, resources) = build_context_and_resources(&self.token_script);
+		let lock_hash_sudt = if self.sudt_owner_mode { resources.scripts.get("lock-1").unwrap().calc_script_hash().unpack() } else { [0u8; 32] };
 
-#[test]
-fn test_multiple_separate_token_sale_cells()
-{
-	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
+		// Prepare header deps.
+		for header in self.header_deps
+		{
+			tx = tx.header_dep(header.hash());
+			context.insert_header(header);
+		}
 
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-	
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 102);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 100, 1, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 100, 1, 1, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_sudt_cell(&mut context, &resources, 100, 2, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 101, 99, 1, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 101, 99, 1, 1, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
+		// Prepare inputs.
+		let mut inputs = vec!();
+		let mut witnesses = vec!();
+		for ScenarioInput { cell, since, block, witness_lock } in self.inputs
+		{
+			let input = match cell
+			{
+				ScenarioCell::Capacity(capacity) => create_input_capacity_cell(&mut context, &resources, capacity),
+				ScenarioCell::TokenSale(capacity, tokens, cost, id) => create_input_token_sale_cell(&mut context, &resources, capacity, tokens, cost, id, self.token_sale_owner_mode, self.sudt_owner_mode),
+				ScenarioCell::TokenSaleArgs(capacity, tokens, args) =>
+				{
+					let (output, output_data) = create_output_token_sale_cell_with_args(&mut context, &resources, capacity, tokens, args, &lock_hash_sudt);
+					let input_out_point = context.create_cell(output, output_data);
+					CellInput::new_builder().previous_output(input_out_point).build()
+				},
+				ScenarioCell::Sudt(capacity, tokens) => create_input_sudt_cell(&mut context, &resources, capacity, tokens, self.sudt_owner_mode),
+			};
+			let (output, output_data) = context.get_cell(&input.previous_output()).expect("cell");
+			let (output, output_data) = set_token_options(output, output_data, &self.token_type_args, &self.token_extension_data);
+			context.create_cell_with_out_point(input.previous_output(), output, output_data);
+			if let Some(header) = block
+			{
+				context.link_cell_with_block(input.previous_output(), header.hash(), 0);
+			}
+			inputs.push(input.as_builder().since(since.pack()).build());
+			witnesses.push(witness_lock.map(|lock| WitnessArgs::new_builder().lock(Some(lock).pack()).build().as_bytes()).unwrap_or_default());
+		}
+
+		// Prepare outputs.
+		let mut outputs = vec!();
+		let mut outputs_data = vec!();
+		for cell in self.outputs
+		{
+			let (output, output_data) = match cell
+			{
+				ScenarioCell::Capacity(capacity) => create_output_capacity_cell(&mut context, &resources, capacity),
+				ScenarioCell::TokenSale(capacity, tokens, cost, id) => create_output_token_sale_cell(&mut context, &resources, capacity, tokens, cost, id, self.token_sale_owner_mode, self.sudt_owner_mode),
+				ScenarioCell::TokenSaleArgs(capacity, tokens, args) => create_output_token_sale_cell_with_args(&mut context, &resources, capacity, tokens, args, &lock_hash_sudt),
+				ScenarioCell::Sudt(capacity, tokens) => create_output_sudt_cell(&mut context, &resources, capacity, tokens, self.sudt_owner_mode),
+			};
+			let (output, output_data) = set_token_options(output, output_data, &self.token_type_args, &self.token_extension_data);
+			outputs.push(output);
+			outputs_data.push(output_data);
+		}
+
+		// Populate the transaction, build, and complete.
+		// Witnesses are only added when required, so that transactions without them are unchanged.
+		let tx = if witnesses.iter().any(|witness: &Bytes| !witness.is_empty()) { tx.witnesses(witnesses.pack()) } else { tx };
+		let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
+		let tx = context.complete_tx(tx);
+
+		(context, resources, tx)
+	}
+
+	/// Execute the transaction and expect it to pass verification. Returns the cycles consumed.
+	/// The transaction is also checked with the validation core on the host, so that it is included in coverage.
+	pub fn expect_pass(self) -> u64
+	{
+		let (context, resources, tx) = self.build();
+		assert_eq!(validate_transaction_host(&context, &resources, &tx), Ok(()));
+		context.verify_tx(&tx, max_cycles()).expect("pass verification")
+	}
+
+	/// Execute the transaction and expect it to be rejected by the Token Sale Lock Script with the specified error.
+	/// The transaction is also checked with the validation core on the host, which must return the same error.
+	pub fn expect_err(self, error: Error)
+	{
+		let (mut context, resources, tx) = self.build();
+		expect_tx_err(&mut context, &resources, &tx, error);
+	}
+}
+
+/// Execute the transaction and expect it to be rejected by the Token Sale Lock Script with the specified error.
+/// The transaction is also checked with the validation core on the host, which must return the same error.
+fn expect_tx_err(context: &mut Context, resources: &LocalResources, tx: &TransactionView, error: Error)
+{
+	assert_eq!(validate_transaction_host(context, resources, tx), Err(error));
+
+	let err = context.verify_tx(tx, max_cycles()).unwrap_err();
+	assert_error_eq!(err, ScriptError::ValidationFailure(error as i8));
+
+	// The error must come from a Token Sale script group, so every other script must pass without them.
+	let token_sale_inputs = replace_token_sale_locks(context, resources, tx);
+	let result = context.verify_tx(tx, max_cycles());
+	for (out_point, cell, data) in token_sale_inputs
+	{
+		context.create_cell_with_out_point(out_point, cell, data);
+	}
+	result.expect("only the Token Sale Lock Script fails");
+}
+
+/// Replace the lock of every input Token Sale Cell with an always success lock and return the original Cells.
+/// The Cells are replaced at the same out points, so the transaction hash and any signatures remain valid.
+fn replace_token_sale_locks(context: &mut Context, resources: &LocalResources, tx: &TransactionView) -> Vec<(OutPoint, CellOutput, Bytes)>
+{
+	let code_hash = CellOutput::calc_data_hash(resources.binaries.get("token-sale").unwrap());
+	let always_success_lock = resources.scripts.get("lock-1").unwrap().clone();
+
+	let mut token_sale_inputs = vec!();
+	for input in tx.inputs().into_iter()
+	{
+		let out_point = input.previous_output();
+		let (cell, data) = context.get_cell(&out_point).expect("cell");
+		if cell.lock().code_hash() == code_hash && cell.lock().hash_type() == ScriptHashType::Data.into()
+		{
+			let lock = always_success_lock.clone().as_builder().args(cell.lock().args()).build();
+			context.create_cell_with_out_point(out_point.clone(), cell.clone().as_builder().lock(lock).build(), data.clone());
+			token_sale_inputs.push((out_point, cell, data));
+		}
+	}
+
+	token_sale_inputs
+}
+
+/// Add an output Cell to a completed transaction.
+fn add_output(tx: &TransactionView, output: CellOutput, output_data: Bytes) -> TransactionView
+{
+	tx.as_advanced_builder().output(output).output_data(output_data.pack()).build()
+}
+
+/// Replace an output Cell of a completed transaction.
+fn replace_output(tx: &TransactionView, index: usize, output: CellOutput, output_data: Bytes) -> TransactionView
+{
+	let mut outputs: Vec<CellOutput> = tx.outputs().into_iter().collect();
+	let mut outputs_data: Vec<Bytes> = tx.outputs_data().into_iter().map(|data| data.unpack()).collect();
+	outputs[index] = output;
+	outputs_data[index] = output_data;
+
+	tx.as_advanced_builder().set_outputs(outputs).set_outputs_data(outputs_data.into_iter().map(|data| data.pack()).collect()).build()
+}
+
+/// Replace an input Cell of a completed transaction with a new Cell.
+fn replace_input(context: &mut Context, tx: &TransactionView, index: usize, output: CellOutput, output_data: Bytes) -> TransactionView
+{
+	let mut inputs: Vec<CellInput> = tx.inputs().into_iter().collect();
+	let input_out_point = context.create_cell(output, output_data);
+	inputs[index] = inputs[index].clone().as_builder().previous_output(input_out_point).build();
+
+	tx.as_advanced_builder().set_inputs(inputs).build()
+}
+
+/// Create a Scenario where one token is bought from each of the specified number of Token Sale Cells, alongside the
+/// specified number of unrelated capacity Cells on each side of the transaction.
+fn create_purchase_scenario(sales: u32, filler_cells: u32) -> Scenario
+{
+	let mut scenario = Scenario::new()
+		.capacity_in(200 + sales as u64)
+		.capacity_out(100)
+		.sudt_out(100, sales as u128);
+
+	for id in 0..sales
+	{
+		scenario = scenario
+			.sale_in(1_000, 100, 1, id)
+			.sale_out(1_001, 99, 1, id);
+	}
+
+	for _ in 0..filler_cells
+	{
+		scenario = scenario
+			.capacity_in(100)
+			.capacity_out(100);
+	}
+
+	scenario
+}
+
+#[test]
+fn test_no_change()
+{
+	Scenario::new()
+		.sale_in(1_000, 1_000, 100, 0)
+		.sale_out(1_000, 1_000, 100, 0)
+		.expect_err(Error::AmountCkbytes);
+}
+
+#[test]
+fn test_buy()
+{
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_out(800)
+		.sale_out(1_100, 99, 100, 0)
+		.sudt_out(100, 1)
+		.expect_pass();
+}
+
+/// Create a purchase where the tokens are added to an existing SUDT Cell of the buyer, which uses the ACP lock.
+/// The ACP Cell is unlocked without a signature, and its output has the specified capacity.
+fn create_anyone_can_pay_purchase(acp_output_capacity: u64) -> (Context, LocalResources, TransactionView)
+{
+	// Constants
+	const ACP_KEY_SEED: u64 = 1; // The seed used to generate the key of the buyer.
+	const ACP_INPUT_INDEX: usize = 1; // The index of the input ACP Cell.
+	const ACP_OUTPUT_INDEX: usize = 2; // The index of the output ACP Cell.
+
+	let (mut context, resources, tx) = Scenario::new()
+		.capacity_in(1_000)
+		.sudt_in(100, 10)
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_out(900)
+		.sale_out(1_100, 99, 100, 0)
+		.sudt_out(100, 11)
+		.build();
+
+	// Move the SUDT Cells to the ACP lock of the buyer.
+	let (acp_lock, acp_deps) = deploy_anyone_can_pay_lock(&mut context, ACP_KEY_SEED);
+	let (input, input_data) = context.get_cell(&tx.inputs().get(ACP_INPUT_INDEX).unwrap().previous_output()).expect("cell");
+	let tx = replace_input(&mut context, &tx, ACP_INPUT_INDEX, input.as_builder().lock(acp_lock.clone()).build(), input_data);
+	let output = tx.outputs().get(ACP_OUTPUT_INDEX).unwrap();
+	let output_data = tx.outputs_data().get(ACP_OUTPUT_INDEX).unwrap().unpack();
+	let tx = replace_output(&tx, ACP_OUTPUT_INDEX, output.as_builder().capacity(acp_output_capacity.pack()).lock(acp_lock).build(), output_data);
+
+	// Every input has an empty witness, so the ACP Cell is unlocked without a signature.
+	let witnesses = vec!(Bytes::new().pack(); tx.inputs().len());
+	let tx = tx.as_advanced_builder().cell_deps(acp_deps).set_witnesses(witnesses).build();
+
+	(context, resources, tx)
+}
+
+#[test]
+#[ignore]
+fn test_buy_into_existing_token_cell()
+{
+	// The purchased tokens are added to the existing ACP Cell of the buyer, so the buyer needs no extra capacity.
+	let (context, resources, tx) = create_anyone_can_pay_purchase(100);
+	assert_eq!(validate_transaction_host(&context, &resources, &tx), Ok(()));
+	context.verify_tx(&tx, max_cycles()).expect("pass verification");
+
+	// The ACP lock rejects capacity being taken from the ACP Cell, even though the Token Sale Lock Script accepts it.
+	let (mut context, resources, tx) = create_anyone_can_pay_purchase(99);
+	assert_eq!(validate_transaction_host(&context, &resources, &tx), Ok(()));
+	context.verify_tx(&tx, max_cycles()).expect_err("the ACP lock rejects the transaction");
+	let token_sale_inputs = replace_token_sale_locks(&mut context, &resources, &tx);
+	assert!(!token_sale_inputs.is_empty());
+	context.verify_tx(&tx, max_cycles()).expect_err("the ACP lock rejects the transaction without the Token Sale Lock Script");
+}
+
+#[test]
+fn test_add_lock()
+{
+	Scenario::new()
+		.capacity_in(100)
+		.sudt_in(100, 100)
+		.capacity_out(100)
+		.sale_out(100, 100, 100, 0)
+		.expect_pass();
+}
+
+#[test]
+fn test_remove_lock()
+{
+	Scenario::new()
+		.token_sale_owner_mode()
+		.capacity_in(100)
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_out(1_000)
+		.sudt_out(100, 100)
+		.expect_pass();
+}
+
+#[test]
+fn test_remove_lock_no_owner()
+{
+	Scenario::new()
+		.capacity_in(100)
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_out(1_000)
+		.sudt_out(100, 100)
+		.expect_err(Error::InvalidStructure);
+}
+
+#[test]
+fn test_split_lock()
+{
+	Scenario::new()
+		.token_sale_owner_mode()
+		.capacity_in(100)
+		.sale_in(100, 100, 100, 0)
+		.sale_out(100, 50, 100, 0)
+		.sale_out(100, 50, 100, 0)
+		.expect_pass();
+}
+
+#[test]
+fn test_split_lock_no_owner()
+{
+	Scenario::new()
+		.sale_in(1_000, 100, 100, 0)
+		.sale_out(500, 50, 100, 0)
+		.sale_out(500, 50, 100, 0)
+		.expect_err(Error::InvalidStructure);
+}
+
+#[test]
+fn test_combine_lock()
+{
+	Scenario::new()
+		.token_sale_owner_mode()
+		.capacity_in(100)
+		.sale_in(100, 50, 100, 0)
+		.sale_in(100, 50, 100, 0)
+		.sale_out(300, 100, 100, 0)
+		.expect_pass();
+}
+
+#[test]
+fn test_combine_lock_no_owner()
+{
+	Scenario::new()
+		.capacity_in(100)
+		.sale_in(100, 50, 100, 0)
+		.sale_in(100, 50, 100, 0)
+		.sale_out(300, 100, 100, 0)
+		.expect_err(Error::InvalidStructure);
+}
+
+#[test]
+fn test_buy_invalid_ckbytes()
+{
+	Scenario::new()
+		.capacity_in(100)
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_out(200)
+		.sale_out(900, 100, 100, 0)
+		.expect_err(Error::AmountCkbytes);
+}
+
+#[test]
+fn test_buy_invalid_sudt()
+{
+	Scenario::new()
+		.capacity_in(100)
+		.sudt_in(100, 100)
+		.sale_in(1_000, 100, 100, 0)
+		.sale_out(1_200, 200, 100, 0)
+		.expect_err(Error::AmountSudt);
+}
+
+#[test]
+fn test_sell()
+{
+	Scenario::new()
+		.sudt_in(100, 1)
+		.sale_in(1_000, 100, 100, 0)
+		.sale_out(900, 101, 100, 0)
+		.capacity_out(200)
+		.expect_err(Error::AmountCkbytes);
+}
+
+#[test]
+fn test_change_cost()
+{
+	Scenario::new()
+		.token_sale_owner_mode()
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_in(100)
+		.sale_out(1_000, 100, 50, 0)
+		.capacity_out(100)
+		.expect_pass();
+}
+
+#[test]
+fn test_change_cost_no_owner()
+{
+	Scenario::new()
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_in(100)
+		.sale_out(1_000, 100, 50, 0)
+		.capacity_out(100)
+		.expect_err(Error::InvalidStructure);
+}
+
+#[test]
+fn test_remove_capacity()
+{
+	Scenario::new()
+		.token_sale_owner_mode()
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_in(100)
+		.sale_out(100, 100, 100, 0)
+		.capacity_out(1_000)
+		.expect_pass();
+}
+
+#[test]
+fn test_remove_capacity_no_owner()
+{
+	Scenario::new()
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_in(100)
+		.sale_out(100, 100, 100, 0)
+		.capacity_out(1_000)
+		.expect_err(Error::AmountCkbytes);
+}
+
+#[test]
+fn test_remove_tokens()
+{
+	Scenario::new()
+		.token_sale_owner_mode()
+		.sale_in(100, 1_100, 100, 0)
+		.capacity_in(100)
+		.sale_out(100, 100, 100, 0)
+		.sudt_out(100, 1_000)
+		.expect_pass();
+}
+
+#[test]
+fn test_remove_tokens_no_owner()
+{
+	Scenario::new()
+		.sale_in(100, 1_100, 100, 0)
+		.capacity_in(200)
+		.sale_out(200, 100, 100, 0)
+		.sudt_out(100, 1_000)
+		.expect_err(Error::ExchangeRate);
+}
+
+#[test]
+fn test_add_tokens()
+{
+	Scenario::new()
+		.token_sale_owner_mode()
+		.sale_in(100, 0, 100, 0)
+		.sudt_in(100, 1_100)
+		.sale_out(100, 1_000, 100, 0)
+		.sudt_out(100, 100)
+		.expect_pass();
+}
+
+#[test]
+fn test_add_tokens_no_owner()
+{
+	Scenario::new()
+		.sale_in(100, 0, 100, 0)
+		.sudt_in(110, 1_100)
+		.sale_out(110, 1_000, 100, 0)
+		.sudt_out(100, 100)
+		.expect_err(Error::AmountSudt);
+}
+
+#[test]
+fn test_add_tokens_dual_owner()
+{
+	Scenario::new()
+		.token_sale_owner_mode()
+		.sudt_owner_mode()
+		.sale_in(100, 0, 100, 0)
+		.capacity_in(100)
+		.sale_out(100, 1_000, 100, 0)
+		.capacity_out(100)
+		.expect_pass();
+}
+
+#[test]
+fn test_invalid_args()
+{
+	// A Token Sale Cell with only a lock hash in the args.
+	let args: Bytes = [0u8; 32].to_vec().into();
+
+	Scenario::new()
+		.sale_args_in(1_000, 1_000, args.clone())
+		.sale_args_out(1_000, 1_000, args)
+		.expect_err(Error::ArgsLen);
+}
+
+#[test]
+fn test_invalid_cost()
+{
+	Scenario::new()
+		.capacity_in(100)
+		.sale_in(100, 100, 0, 0)
+		.capacity_out(100)
+		.sale_out(100, 100, 0, 0)
+		.expect_err(Error::InvalidCost);
+}
+
+#[test]
+fn test_multiple_separate_token_sale_cells()
+{
+	Scenario::new()
+		.capacity_in(102)
+		.sale_in(100, 100, 1, 0)
+		.sale_in(100, 100, 1, 1)
+		.sudt_out(100, 2)
+		.sale_out(101, 99, 1, 0)
+		.sale_out(101, 99, 1, 1)
+		.expect_pass();
+}
+
+#[test]
+fn test_multiple_separate_token_sale_cells_invalid()
+{
+	Scenario::new()
+		.capacity_in(102)
+		.sale_in(100, 100, 1, 0)
+		.sale_in(100, 100, 1, 0)
+		.sudt_out(100, 2)
+		.sale_out(101, 99, 1, 0)
+		.sale_out(101, 99, 1, 0)
+		.expect_err(Error::InvalidStructure);
+}
+
+#[test]
+fn test_validate_transaction_host_buy()
+{
+	let (context, resources, tx) = Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_out(800)
+		.sale_out(1_100, 99, 100, 0)
+		.sudt_out(100, 1)
+		.build();
+
+	// Validate the transaction on the host, then execute the transaction.
+	validate_transaction_host(&context, &resources, &tx).expect("pass host validation");
+	let _cycles = context.verify_tx(&tx, max_cycles()).expect("pass verification");
+}
+
+#[test]
+fn test_validate_transaction_host_invalid_exchange_rate()
+{
+	let (context, resources, tx) = Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_out(850)
+		.sale_out(1_050, 99, 100, 0)
+		.sudt_out(100, 1)
+		.build();
+
+	// Validate the transaction on the host, then execute the transaction.
+	let host_err = validate_transaction_host(&context, &resources, &tx).unwrap_err();
+	assert_eq!(host_err, Error::ExchangeRate);
+	let err = context.verify_tx(&tx, max_cycles()).unwrap_err();
+	assert_error_eq!(err, ScriptError::ValidationFailure(Error::ExchangeRate as i8));
+}
+
+#[test]
+fn test_buy_cost_overflow()
+{
+	// The token amount multiplied by the cost overflows a u128.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, u128::MAX, u64::MAX, 0)
+		.capacity_out(800)
+		.sale_out(1_100, 0, u64::MAX, 0)
+		.sudt_out(100, u128::MAX)
+		.expect_err(Error::ExchangeRate);
+}
+
+#[test]
+fn test_cycles()
+{
+	// Measure the canonical transactions.
+	let measured = vec!
+	(
+		("buy", Scenario::new()
+			.capacity_in(1_000)
+			.sale_in(1_000, 100, 100, 0)
+			.capacity_out(800)
+			.sale_out(1_100, 99, 100, 0)
+			.sudt_out(100, 1)
+			.expect_pass()),
+		("owner-refill", Scenario::new()
+			.token_sale_owner_mode()
+			.sale_in(100, 0, 100, 0)
+			.sudt_in(100, 1_100)
+			.sale_out(100, 1_000, 100, 0)
+			.sudt_out(100, 100)
+			.expect_pass()),
+		("multi-sale", Scenario::new()
+			.capacity_in(102)
+			.sale_in(100, 100, 1, 0)
+			.sale_in(100, 100, 1, 1)
+			.sudt_out(100, 2)
+			.sale_out(101, 99, 1, 0)
+			.sale_out(101, 99, 1, 1)
+			.expect_pass()),
+	);
+
+	// Load the baselines for the current test environment.
+	let test_env = env::var(TEST_ENV_VAR).unwrap_or_else(|_| "debug".to_owned()).to_lowercase();
+	let tolerance: u64 = env::var(CYCLES_TOLERANCE_ENV_VAR).map(|tolerance| tolerance.parse().expect("cycles tolerance")).unwrap_or(DEFAULT_CYCLES_TOLERANCE);
+	let update = env::var(CYCLES_UPDATE_ENV_VAR).is_ok();
+	let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(CYCLES_FILE);
+	let mut baselines = load_cycle_baselines(&path, update);
+
+	// Record every measurement when updating, otherwise compare each against its baseline.
+	for (name, cycles) in measured
+	{
+		let key = format!("{} {}", test_env, name);
+		if update
+		{
+			baselines.insert(key, cycles);
+			continue;
+		}
+
+		let baseline = *baselines.get(&key).unwrap_or_else(|| panic!("No cycle baseline for {}. Run with {}=1 to record it.", key, CYCLES_UPDATE_ENV_VAR));
+		let limit = baseline + baseline * tolerance / 100;
+		assert!(cycles <= limit, "Cycles for {} regressed from {} to {}, exceeding the {}% tolerance. Run with {}=1 if this is expected.", key, baseline, cycles, tolerance, CYCLES_UPDATE_ENV_VAR);
+	}
+
+	if update
+	{
+		save_cycle_baselines(&path, &baselines);
+	}
+}
+
+#[test]
+#[ignore]
+fn test_token_script_matrix()
+{
+	for (name, _) in DEPLOYED_TOKEN_SCRIPTS.iter()
+	{
+		require_token_script(name);
+	}
+
+	let (_context, _tx, resources) = build_default_context_and_resources();
+	let mut token_scripts: Vec<&String> = resources.token_scripts.keys().collect();
+	token_scripts.sort();
+
+	for token_script in token_scripts
+	{
+		// Buy tokens.
+		Scenario::new()
+			.token_script(token_script)
+			.capacity_in(1_000)
+			.sale_in(1_000, 100, 100, 0)
+			.capacity_out(800)
+			.sale_out(1_100, 99, 100, 0)
+			.sudt_out(100, 1)
+			.expect_pass();
+
+		// Refill tokens in owner mode.
+		Scenario::new()
+			.token_script(token_script)
+			.token_sale_owner_mode()
+			.sale_in(100, 0, 100, 0)
+			.sudt_in(100, 1_100)
+			.sale_out(100, 1_000, 100, 0)
+			.sudt_out(100, 100)
+			.expect_pass();
+
+		// Underpay for tokens.
+		Scenario::new()
+			.token_script(token_script)
+			.capacity_in(1_000)
+			.sale_in(1_000, 100, 100, 0)
+			.capacity_out(850)
+			.sale_out(1_050, 99, 100, 0)
+			.sudt_out(100, 1)
+			.expect_err(Error::ExchangeRate);
+
+		// Remove the lock without the owner.
+		Scenario::new()
+			.token_script(token_script)
+			.capacity_in(100)
+			.sale_in(1_000, 100, 100, 0)
+			.capacity_out(1_000)
+			.sudt_out(100, 100)
+			.expect_err(Error::InvalidStructure);
+	}
+}
+
+#[test]
+fn test_core_validate_amounts()
+{
+	use token_sale_core::validate_amounts;
+
+	// Buy 3 tokens at a cost of 2.
+	assert_eq!(validate_amounts(2, 1, false, 100, 106, 10, 7), Ok(()));
+	assert_eq!(validate_amounts(1, 1, false, 100, 101, 1, 0), Ok(()));
+
+	// The output capacity must be strictly more than the input capacity.
+	assert_eq!(validate_amounts(2, 1, false, 100, 100, 10, 7), Err(Error::AmountCkbytes));
+	assert_eq!(validate_amounts(2, 1, false, 100, 99, 10, 7), Err(Error::AmountCkbytes));
+
+	// The output tokens must be strictly less than the input tokens.
+	assert_eq!(validate_amounts(2, 1, false, 100, 106, 10, 10), Err(Error::AmountSudt));
+	assert_eq!(validate_amounts(2, 1, false, 100, 106, 10, 11), Err(Error::AmountSudt));
+
+	// The capacity received must exactly equal the tokens sold multiplied by the cost.
+	assert_eq!(validate_amounts(2, 1, false, 100, 105, 10, 7), Err(Error::ExchangeRate));
+	assert_eq!(validate_amounts(2, 1, false, 100, 107, 10, 7), Err(Error::ExchangeRate));
+	assert_eq!(validate_amounts(u64::MAX, 1, false, 0, u64::MAX, u128::MAX, 0), Err(Error::ExchangeRate));
+
+	// Buy 10 tokens at a cost of 1 per 2 tokens, and 6 tokens at a cost of 100 per 3 tokens.
+	assert_eq!(validate_amounts(1, 2, false, 100, 105, 10, 0), Ok(()));
+	assert_eq!(validate_amounts(100, 3, false, 100, 300, 10, 4), Ok(()));
+
+	// A fractional amount of capacity can never be paid.
+	assert_eq!(validate_amounts(1, 2, false, 100, 105, 11, 0), Err(Error::ExchangeRate));
+	assert_eq!(validate_amounts(100, 3, false, 100, 133, 10, 9), Err(Error::ExchangeRate));
+	assert_eq!(validate_amounts(100, 3, false, 100, 134, 10, 9), Err(Error::ExchangeRate));
+
+	// The largest amounts are compared without overflowing.
+	assert_eq!(validate_amounts(u64::MAX, u64::MAX, false, 0, 1, 1, 0), Ok(()));
+	assert_eq!(validate_amounts(u64::MAX, u64::MAX, false, 0, u64::MAX, u64::MAX as u128, 0), Ok(()));
+
+	// Overpayment allows more capacity than the cost, but never less.
+	assert_eq!(validate_amounts(2, 1, true, 100, 106, 10, 7), Ok(()));
+	assert_eq!(validate_amounts(2, 1, true, 100, 107, 10, 7), Ok(()));
+	assert_eq!(validate_amounts(2, 1, true, 100, 105, 10, 7), Err(Error::ExchangeRate));
+	assert_eq!(validate_amounts(100, 3, true, 100, 334, 10, 3), Ok(()));
+	assert_eq!(validate_amounts(100, 3, true, 100, 333, 10, 3), Err(Error::ExchangeRate));
+	assert_eq!(validate_amounts(u64::MAX, 1, true, 0, u64::MAX, u128::MAX, 0), Err(Error::ExchangeRate));
+
+	// Overpayment still requires tokens to be sold.
+	assert_eq!(validate_amounts(2, 1, true, 100, 106, 10, 10), Err(Error::AmountSudt));
+}
+
+#[test]
+fn test_core_quote_purchase()
+{
+	use token_sale_core::{quote_purchase, validate_amounts, Quote, FLAG_OVERPAYMENT, FLAG_PRICE_DENOMINATOR};
+
+	// Buy 3 tokens at a cost of 2.
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, 2);
+	let quote = Quote { capacity_required: 6, output_capacity: 106, output_tokens: 7 };
+	assert_eq!(quote_purchase(&args, 100, 10, 3), Ok(quote));
+	assert_eq!(validate_amounts(2, 1, false, 100, quote.output_capacity, 10, quote.output_tokens), Ok(()));
+
+	// At least one token must be bought, and no more than the Token Sale Cell holds.
+	assert_eq!(quote_purchase(&args, 100, 10, 10).map(|quote| quote.output_tokens), Ok(0));
+	assert_eq!(quote_purchase(&args, 100, 10, 0), Err(Error::AmountSudt));
+	assert_eq!(quote_purchase(&args, 100, 10, 11), Err(Error::AmountSudt));
+
+	// The args are validated in the same way as the contract.
+	assert_eq!(quote_purchase(&args[..39], 100, 10, 3), Err(Error::ArgsLen));
+	assert_eq!(quote_purchase(&create_core_args(&CORE_OWNER_LOCK_HASH, 0), 100, 10, 3), Err(Error::InvalidCost));
+
+	// Buy 6 tokens at a cost of 100 per 3 tokens. A fractional amount of capacity can never be paid.
+	let args = create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_PRICE_DENOMINATOR, &3u64.to_le_bytes());
+	assert_eq!(quote_purchase(&args, 100, 10, 6).map(|quote| quote.capacity_required), Ok(200));
+	assert_eq!(quote_purchase(&args, 100, 10, 1), Err(Error::ExchangeRate));
+
+	// Overpayment rounds a fractional amount of capacity up.
+	let args = create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_PRICE_DENOMINATOR | FLAG_OVERPAYMENT, &3u64.to_le_bytes());
+	let quote = quote_purchase(&args, 100, 10, 1).unwrap();
+	assert_eq!(quote.capacity_required, 34);
+	assert_eq!(validate_amounts(100, 3, true, 100, quote.output_capacity, 10, quote.output_tokens), Ok(()));
+	assert_eq!(validate_amounts(100, 3, true, 100, quote.output_capacity - 1, 10, quote.output_tokens), Err(Error::ExchangeRate));
+
+	// A cost which does not fit in a u64 can never be paid.
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, u64::MAX);
+	assert_eq!(quote_purchase(&args, 0, u128::MAX, 2), Err(Error::ExchangeRate));
+	assert_eq!(quote_purchase(&args, u64::MAX, u128::MAX, 1), Err(Error::AmountCkbytes));
+}
+
+#[test]
+fn test_core_determine_occupied_capacity()
+{
+	use token_sale_core::determine_occupied_capacity;
+
+	// A secp256k1 Cell which receives SUDT tokens.
+	let lock_script = Script::new_builder().args(Bytes::from(vec![0u8; 20]).pack()).build();
+	let type_script = Script::new_builder().args(Bytes::from(vec![0u8; 32]).pack()).build();
+	let cell = CellOutput::new_builder().lock(lock_script).type_(Some(type_script).pack()).build();
+	let occupied_capacity = cell.occupied_capacity(Capacity::bytes(16).unwrap()).unwrap().as_u64();
+	assert_eq!(determine_occupied_capacity(20, Some(32), 16), occupied_capacity);
+	assert_eq!(occupied_capacity, 142 * 100_000_000);
+
+	// A Cell without a Type Script or data.
+	assert_eq!(determine_occupied_capacity(20, None, 0), 61 * 100_000_000);
+}
+
+#[test]
+fn test_core_validate_buy_back_amounts()
+{
+	use token_sale_core::validate_buy_back_amounts;
+
+	// A valid buy-back of 3 tokens at a buy-back cost of 2.
+	assert_eq!(validate_buy_back_amounts(2, 1, false, 106, 100, 7, 10), Ok(()));
+
+	// The output capacity must be strictly less than the input capacity.
+	assert_eq!(validate_buy_back_amounts(2, 1, false, 100, 100, 7, 10), Err(Error::AmountCkbytes));
+	assert_eq!(validate_buy_back_amounts(2, 1, false, 100, 106, 7, 10), Err(Error::AmountCkbytes));
+
+	// The output tokens must be strictly more than the input tokens.
+	assert_eq!(validate_buy_back_amounts(2, 1, false, 106, 100, 10, 10), Err(Error::AmountSudt));
+	assert_eq!(validate_buy_back_amounts(2, 1, false, 106, 100, 11, 10), Err(Error::AmountSudt));
+
+	// The capacity paid must exactly equal the tokens returned multiplied by the buy-back cost.
+	assert_eq!(validate_buy_back_amounts(2, 1, false, 107, 100, 7, 10), Err(Error::ExchangeRate));
+	assert_eq!(validate_buy_back_amounts(2, 1, false, 105, 100, 7, 10), Err(Error::ExchangeRate));
+	assert_eq!(validate_buy_back_amounts(u64::MAX, 1, false, u64::MAX, 0, 0, u128::MAX), Err(Error::ExchangeRate));
+
+	// A buy-back of 6 tokens at a buy-back cost of 90 per 3 tokens.
+	assert_eq!(validate_buy_back_amounts(90, 3, false, 300, 120, 4, 10), Ok(()));
+	assert_eq!(validate_buy_back_amounts(90, 3, false, 300, 121, 4, 10), Err(Error::ExchangeRate));
+
+	// Overpayment allows less capacity to be withdrawn than the buy-back cost, but never more.
+	assert_eq!(validate_buy_back_amounts(2, 1, true, 106, 100, 7, 10), Ok(()));
+	assert_eq!(validate_buy_back_amounts(2, 1, true, 105, 100, 7, 10), Ok(()));
+	assert_eq!(validate_buy_back_amounts(2, 1, true, 107, 100, 7, 10), Err(Error::ExchangeRate));
+
+	// With overpayment, a buy-back cost which overflows is owed more than any capacity which can be withdrawn.
+	assert_eq!(validate_buy_back_amounts(u64::MAX, 1, true, u64::MAX, 0, 0, u128::MAX), Ok(()));
+	assert_eq!(validate_buy_back_amounts(u64::MAX, u64::MAX, true, u64::MAX, 0, 0, u128::MAX), Ok(()));
+}
+
+#[test]
+fn test_core_determine_token_cost()
+{
+	use token_sale_core::determine_token_cost;
+
+	assert_eq!(determine_token_cost(&create_core_args(&CORE_OWNER_LOCK_HASH, 0)), Err(Error::InvalidCost));
+	assert_eq!(determine_token_cost(&create_core_args(&CORE_OWNER_LOCK_HASH, 1)), Ok(1));
+	assert_eq!(determine_token_cost(&create_core_args(&CORE_OWNER_LOCK_HASH, u64::MAX)), Ok(u64::MAX));
+
+	// The unique identifier following the cost is ignored.
+	let mut args = create_core_args(&CORE_OWNER_LOCK_HASH, 100);
+	args.append(&mut u32::MAX.to_le_bytes().to_vec());
+	assert_eq!(determine_token_cost(&args), Ok(100));
+}
+
+#[test]
+fn test_core_determine_sale_options()
+{
+	use token_sale_core::{determine_sale_options, SaleOptions, FLAG_BUY_BACK, FLAG_PRICE_DENOMINATOR, FLAG_SALE_START, FLAG_WHITELIST};
+
+	let start = 10u64.to_le_bytes();
+
+	// Args without flags, including the flags byte with no flags set.
+	assert_eq!(determine_sale_options(&create_core_args(&CORE_OWNER_LOCK_HASH, 100)), Ok(SaleOptions::default()));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, 0, &[])[..44]), Ok(SaleOptions::default()));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, 0, &[])), Ok(SaleOptions::default()));
+
+	// The fields follow in the order of the flag bits.
+	let both = [&start[..], &[7u8; 32][..]].concat();
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_SALE_START, &start)), Ok(SaleOptions { flags: FLAG_SALE_START, sale_start: Some(10), ..SaleOptions::default() }));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_WHITELIST, &[7u8; 32])), Ok(SaleOptions { flags: FLAG_WHITELIST, whitelist_root: Some([7u8; 32]), ..SaleOptions::default() }));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_SALE_START | FLAG_WHITELIST, &both)), Ok(SaleOptions { flags: FLAG_SALE_START | FLAG_WHITELIST, sale_start: Some(10), whitelist_root: Some([7u8; 32]), ..SaleOptions::default() }));
+
+	// A buy-back cost of 0 is invalid.
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_BUY_BACK, &90u64.to_le_bytes())), Ok(SaleOptions { flags: FLAG_BUY_BACK, buy_back_cost: Some(90), ..SaleOptions::default() }));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_BUY_BACK, &0u64.to_le_bytes())), Err(Error::InvalidCost));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_BUY_BACK, &100u64.to_le_bytes())), Ok(SaleOptions { flags: FLAG_BUY_BACK, buy_back_cost: Some(100), ..SaleOptions::default() }));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_BUY_BACK, &101u64.to_le_bytes())), Err(Error::InvalidCost));
+
+	// A price denominator of 0 is invalid.
+	let costs = [90u64.to_le_bytes(), 3u64.to_le_bytes()].concat();
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_PRICE_DENOMINATOR, &3u64.to_le_bytes())), Ok(SaleOptions { flags: FLAG_PRICE_DENOMINATOR, price_denominator: Some(3), ..SaleOptions::default() }));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_BUY_BACK | FLAG_PRICE_DENOMINATOR, &costs)), Ok(SaleOptions { flags: FLAG_BUY_BACK | FLAG_PRICE_DENOMINATOR, buy_back_cost: Some(90), price_denominator: Some(3), ..SaleOptions::default() }));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_PRICE_DENOMINATOR, &0u64.to_le_bytes())), Err(Error::InvalidCost));
+
+	// The fields must be complete and nothing may follow them.
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_SALE_START, &start[..7])), Err(Error::ArgsLen));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_WHITELIST, &[7u8; 31])), Err(Error::ArgsLen));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_SALE_START | FLAG_WHITELIST, &start)), Err(Error::ArgsLen));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, FLAG_SALE_START, &both)), Err(Error::ArgsLen));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, 0, &[0])), Err(Error::ArgsLen));
+
+	// Unknown flags are rejected, including the reserved sale end flag.
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, 0x80, &[])), Err(Error::InvalidFlags));
+	assert_eq!(determine_sale_options(&create_flagged_args(&CORE_OWNER_LOCK_HASH, 100, 0, 0x02, &20u64.to_le_bytes())), Err(Error::InvalidFlags));
+}
+
+#[test]
+fn test_core_compare_epochs()
+{
+	use core::cmp::Ordering;
+	use token_sale_core::compare_epochs;
+
+	let epoch = |number, index, length| EpochNumberWithFraction::new(number, index, length).full_value();
+
+	assert_eq!(compare_epochs(epoch(10, 0, 1), epoch(10, 0, 1_800)), Ordering::Equal);
+	assert_eq!(compare_epochs(epoch(10, 1, 2), epoch(10, 900, 1_800)), Ordering::Equal);
+	assert_eq!(compare_epochs(epoch(10, 1, 1_800), epoch(10, 0, 1)), Ordering::Greater);
+	assert_eq!(compare_epochs(epoch(9, 1_799, 1_800), epoch(10, 0, 1)), Ordering::Less);
+	assert_eq!(compare_epochs(epoch(11, 0, 1_800), epoch(10, 1_799, 1_800)), Ordering::Greater);
+
+	// A length of 0 has no fraction.
+	assert_eq!(compare_epochs(10 | 5 << 24, epoch(10, 0, 1)), Ordering::Equal);
+}
+
+#[test]
+fn test_core_validate_sale_start()
+{
+	use token_sale_core::{validate_sale_start, Cell, SaleOptions, FLAG_SALE_START};
+
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, 100);
+	let lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &args);
+	let start = SaleOptions { flags: FLAG_SALE_START, sale_start: Some(EpochNumberWithFraction::new(10, 0, 1).full_value()), ..SaleOptions::default() };
+	let sale_cell = |since| Cell { since, ..create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, None, &[]) };
+	let epoch_since = |number, index, length| create_since_epoch(EpochNumberWithFraction::new(number, index, length), false);
+
+	// Without a start any since is allowed.
+	assert_eq!(validate_sale_start(&SaleOptions::default(), &lock, &[sale_cell(0)]), Ok(()));
+
+	// The since must be an absolute epoch on or after the start.
+	assert_eq!(validate_sale_start(&start, &lock, &[sale_cell(epoch_since(10, 0, 1))]), Ok(()));
+	assert_eq!(validate_sale_start(&start, &lock, &[sale_cell(epoch_since(1_000, 0, 1))]), Ok(()));
+	assert_eq!(validate_sale_start(&start, &lock, &[sale_cell(epoch_since(9, 1_799, 1_800))]), Err(Error::SaleWindow));
+	assert_eq!(validate_sale_start(&start, &lock, &[sale_cell(0)]), Err(Error::SaleWindow));
+	assert_eq!(validate_sale_start(&start, &lock, &[sale_cell(create_since_epoch(EpochNumberWithFraction::new(15, 0, 1), true))]), Err(Error::SaleWindow));
+	assert_eq!(validate_sale_start(&start, &lock, &[sale_cell(create_since_block_number(15, false))]), Err(Error::SaleWindow));
+	assert_eq!(validate_sale_start(&start, &lock, &[sale_cell(create_since_timestamp(15, false))]), Err(Error::SaleWindow));
+}
+
+#[test]
+fn test_core_calculate_merkle_root()
+{
+	use token_sale_core::calculate_merkle_root;
+
+	let leaves: Vec<[u8; 32]> = (0..5u8).map(|i| [i; 32]).collect();
+	for index in 0..leaves.len()
+	{
+		let (root, proof) = create_merkle_proof(&leaves, index);
+		assert_eq!(calculate_merkle_root(&leaves[index], &proof), Some(root));
+	}
+
+	// A single leaf is its own root.
+	assert_eq!(calculate_merkle_root(&leaves[0], &[]), Some(leaves[0]));
+
+	// Malformed leaves and proofs.
+	let (_root, proof) = create_merkle_proof(&leaves, 0);
+	assert_eq!(calculate_merkle_root(&leaves[0][..31], &proof), None);
+	assert_eq!(calculate_merkle_root(&leaves[0], &proof[..proof.len() - 1]), None);
+}
+
+#[test]
+fn test_core_validate_whitelist()
+{
+	use token_sale_core::{validate_whitelist, Cell, SaleOptions, FLAG_WHITELIST};
+
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, 100);
+	let lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &args);
+	let other_lock = create_core_script(&CORE_SUDT_CODE_HASH, &[]);
+	let type_script = create_core_script(&CORE_SUDT_CODE_HASH, &[]);
+	let leaves = [[5u8; 32], CORE_OTHER_LOCK_HASH, [6u8; 32]];
+	let (root, proof) = create_merkle_proof(&leaves, 1);
+	let (_root, other_proof) = create_merkle_proof(&leaves, 0);
+	let whitelist = SaleOptions { flags: FLAG_WHITELIST, whitelist_root: Some(root), ..SaleOptions::default() };
+	let (one, five, six) = (1u128.to_le_bytes(), 5u128.to_le_bytes(), 6u128.to_le_bytes());
+	let buyer_cell = create_core_cell(100, other_lock, &CORE_OTHER_LOCK_HASH, None, &[]);
+	let buyer_token_cell = |data| create_core_cell(100, other_lock, &CORE_OTHER_LOCK_HASH, Some(type_script), data);
+	let sale_cell = |witness_lock| Cell { witness_lock, ..create_core_cell(100, lock, &CORE_OWNER_LOCK_HASH, None, &[]) };
+	let witness_0 = create_whitelist_witness_lock(0, &proof);
+	let witness_1 = create_whitelist_witness_lock(1, &proof);
+	let witness_2 = create_whitelist_witness_lock(2, &proof);
+	let other_witness = create_whitelist_witness_lock(0, &other_proof);
+
+	// Without a whitelist no proof is required.
+	assert_eq!(validate_whitelist(&SaleOptions::default(), &lock, &type_script, &[sale_cell(&[])], &[], 100, 99), Ok(()));
+
+	// The proof must lead from the lock hash of the input Cell at the index to the root.
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_cell, sale_cell(&witness_0)], &[buyer_token_cell(&one)], 100, 99), Ok(()));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[sale_cell(&witness_1), buyer_cell], &[buyer_token_cell(&one)], 100, 99), Ok(()));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_cell, sale_cell(&witness_1)], &[buyer_token_cell(&one)], 100, 99), Err(Error::Whitelist));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_cell, sale_cell(&witness_2)], &[buyer_token_cell(&one)], 100, 99), Err(Error::Whitelist));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_cell, sale_cell(&other_witness)], &[buyer_token_cell(&one)], 100, 99), Err(Error::Whitelist));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_cell, sale_cell(&witness_0[..witness_0.len() - 1])], &[buyer_token_cell(&one)], 100, 99), Err(Error::Whitelist));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_cell, sale_cell(&[0u8; 3])], &[buyer_token_cell(&one)], 100, 99), Err(Error::Whitelist));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_cell, sale_cell(&[])], &[buyer_token_cell(&one)], 100, 99), Err(Error::Whitelist));
+
+	// The whitelisted lock must receive all the tokens sold.
+	let other_token_cell = create_core_cell(100, other_lock, &[9u8; 32], Some(type_script), &one);
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_cell, sale_cell(&witness_0)], &[other_token_cell], 100, 99), Err(Error::Whitelist));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_cell, sale_cell(&witness_0)], &[buyer_token_cell(&one)], 100, 98), Err(Error::Whitelist));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_token_cell(&five), sale_cell(&witness_0)], &[buyer_token_cell(&six)], 100, 99), Ok(()));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_token_cell(&five), sale_cell(&witness_0)], &[buyer_token_cell(&five)], 100, 99), Err(Error::Whitelist));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_cell, sale_cell(&witness_0)], &[buyer_token_cell(&one[..15])], 100, 99), Err(Error::Encoding));
+
+	// The whitelisted lock must provide all the tokens bought back.
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_token_cell(&one), sale_cell(&witness_0)], &[], 99, 100), Ok(()));
+	assert_eq!(validate_whitelist(&whitelist, &lock, &type_script, &[buyer_token_cell(&one), sale_cell(&witness_0)], &[buyer_token_cell(&one)], 99, 100), Err(Error::Whitelist));
+}
+
+#[test]
+fn test_core_check_owner_mode()
+{
+	use token_sale_core::check_owner_mode;
+
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, 100);
+	let lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &args);
+	let owner_cell = create_core_cell(100, lock, &CORE_OWNER_LOCK_HASH, None, &[]);
+	let other_cell = create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, None, &[]);
+
+	assert!(check_owner_mode(&args, &[other_cell, owner_cell]));
+	assert!(!check_owner_mode(&args, &[other_cell]));
+	assert!(!check_owner_mode(&args, &[]));
+}
+
+#[test]
+fn test_core_determine_token_sale_cell_amounts()
+{
+	use token_sale_core::determine_token_sale_cell_amounts;
+
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, 100);
+	let other_args = create_core_args(&CORE_OTHER_LOCK_HASH, 100);
+	let lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &args);
+	let other_lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &other_args);
+	let type_ = create_core_script(&CORE_SUDT_CODE_HASH, &CORE_OWNER_LOCK_HASH);
+	let other_type = create_core_script(&CORE_SUDT_CODE_HASH, &CORE_OTHER_LOCK_HASH);
+	let data_10 = 10u128.to_le_bytes();
+	let data_20 = 20u128.to_le_bytes();
+	let data_short = [0u8; 15];
+
+	// Only Cells matching both the Lock Script and Type Script are counted.
+	let cells = vec!
+	(
+		create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_10),
+		create_core_cell(200, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_20),
+		create_core_cell(400, other_lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_10),
+		create_core_cell(800, lock, &CORE_OTHER_LOCK_HASH, Some(other_type), &data_10),
+		create_core_cell(1_600, lock, &CORE_OTHER_LOCK_HASH, None, &data_10),
+	);
+	assert_eq!(determine_token_sale_cell_amounts(&lock, &type_, &cells), Ok((300, 30)));
+	assert_eq!(determine_token_sale_cell_amounts(&lock, &type_, &[]), Ok((0, 0)));
+
+	// The token amount must be at least 16 bytes.
+	let cells = vec!(create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_short));
+	assert_eq!(determine_token_sale_cell_amounts(&lock, &type_, &cells), Err(Error::Encoding));
+
+	// The totals must not overflow.
+	let data_max = u128::MAX.to_le_bytes();
+	let cells = vec!(create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_max), create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_10));
+	assert_eq!(determine_token_sale_cell_amounts(&lock, &type_, &cells), Err(Error::AmountSudt));
+	let cells = vec!(create_core_cell(u64::MAX, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_10), create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_10));
+	assert_eq!(determine_token_sale_cell_amounts(&lock, &type_, &cells), Err(Error::AmountCkbytes));
+}
+
+#[test]
+fn test_core_validate_token_sale_inputs_and_outputs()
+{
+	use token_sale_core::{validate_token_sale_inputs, validate_token_sale_outputs, SaleOptions, FLAG_MULTIPLE_CELLS};
+
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, 100);
+	let lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &args);
+	let type_ = create_core_script(&CORE_SUDT_CODE_HASH, &CORE_OWNER_LOCK_HASH);
+	let other_type = create_core_script(&CORE_SUDT_CODE_HASH, &CORE_OTHER_LOCK_HASH);
+	let data = 10u128.to_le_bytes();
+	let sale_cell = create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data);
+	let no_type_cell = create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, None, &data);
+	let other_type_cell = create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(other_type), &data);
+
+	let options = SaleOptions::default();
+	let multiple_options = SaleOptions { flags: FLAG_MULTIPLE_CELLS, ..SaleOptions::default() };
+
+	// There must be exactly one input Token Sale Cell and it must have a Type Script.
+	assert_eq!(validate_token_sale_inputs(&options, &lock, &[sale_cell]), Ok(type_));
+	assert_eq!(validate_token_sale_inputs(&options, &lock, &[]), Err(Error::IndexOutOfBound));
+	assert_eq!(validate_token_sale_inputs(&options, &lock, &[sale_cell, sale_cell]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_inputs(&options, &lock, &[no_type_cell]), Err(Error::InvalidStructure));
+
+	// Multiple input Token Sale Cells are allowed when enabled, but must all have the same Type Script.
+	assert_eq!(validate_token_sale_inputs(&multiple_options, &lock, &[sale_cell]), Ok(type_));
+	assert_eq!(validate_token_sale_inputs(&multiple_options, &lock, &[sale_cell, sale_cell, sale_cell]), Ok(type_));
+	assert_eq!(validate_token_sale_inputs(&multiple_options, &lock, &[]), Err(Error::IndexOutOfBound));
+	assert_eq!(validate_token_sale_inputs(&multiple_options, &lock, &[sale_cell, other_type_cell]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_inputs(&multiple_options, &lock, &[sale_cell, no_type_cell]), Err(Error::InvalidStructure));
+
+	// There must be exactly one output Token Sale Cell with a matching Type Script, and no other Cell may use the lock.
+	assert_eq!(validate_token_sale_outputs(&lock, &type_, &[sale_cell], &[sale_cell]), Ok(()));
+	assert_eq!(validate_token_sale_outputs(&lock, &type_, &[sale_cell], &[]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_outputs(&lock, &type_, &[sale_cell], &[other_type_cell]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_outputs(&lock, &type_, &[sale_cell], &[no_type_cell]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_outputs(&lock, &type_, &[sale_cell], &[sale_cell, sale_cell]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_outputs(&lock, &type_, &[sale_cell], &[sale_cell, other_type_cell]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_outputs(&lock, &type_, &[sale_cell], &[other_type_cell, sale_cell]), Err(Error::InvalidStructure));
+
+	// Multiple input Token Sale Cells require the same number of output Token Sale Cells.
+	assert_eq!(validate_token_sale_outputs(&lock, &type_, &[sale_cell, sale_cell], &[sale_cell, sale_cell]), Ok(()));
+	assert_eq!(validate_token_sale_outputs(&lock, &type_, &[sale_cell, sale_cell], &[sale_cell]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_outputs(&lock, &type_, &[sale_cell, sale_cell], &[sale_cell, sale_cell, sale_cell]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_outputs(&lock, &type_, &[sale_cell, sale_cell], &[sale_cell, other_type_cell]), Err(Error::InvalidStructure));
+}
+
+#[test]
+fn test_core_validate_token_sale_data()
+{
+	use token_sale_core::validate_token_sale_data;
+
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, 100);
+	let lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &args);
+	let other_args = create_core_args(&CORE_OWNER_LOCK_HASH, 101);
+	let other_lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &other_args);
+	let type_ = create_core_script(&CORE_SUDT_CODE_HASH, &CORE_OWNER_LOCK_HASH);
+	let data_in = create_xudt_data(10, &[1, 2, 3]);
+	let data_out = create_xudt_data(9, &[1, 2, 3]);
+	let data_altered = create_xudt_data(9, &[1, 2, 4]);
+	let data_appended = create_xudt_data(9, &[1, 2, 3, 4]);
+	let data_removed = create_xudt_data(9, &[]);
+	let sale_in = create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_in);
+	let sale_out = create_core_cell(200, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_out);
+	let other_sale_out = create_core_cell(200, other_lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_altered);
+
+	// Only the token amount may change.
+	assert_eq!(validate_token_sale_data(&lock, &[sale_in], &[sale_out]), Ok(()));
+	assert_eq!(validate_token_sale_data(&lock, &[sale_in], &[other_sale_out, sale_out]), Ok(()));
+	for data in [&data_altered, &data_appended, &data_removed].iter()
+	{
+		let sale_out = create_core_cell(200, lock, &CORE_OTHER_LOCK_HASH, Some(type_), data);
+		assert_eq!(validate_token_sale_data(&lock, &[sale_in], &[sale_out]), Err(Error::InvalidStructure));
+	}
+
+	// Both Token Sale Cells must exist.
+	assert_eq!(validate_token_sale_data(&lock, &[], &[sale_out]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_data(&lock, &[sale_in], &[other_sale_out]), Err(Error::InvalidStructure));
+
+	// Multiple Token Sale Cells are paired in order.
+	let other_data_in = create_xudt_data(10, &[4, 5]);
+	let other_data_out = create_xudt_data(9, &[4, 5]);
+	let other_in = create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &other_data_in);
+	let other_out = create_core_cell(200, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &other_data_out);
+	assert_eq!(validate_token_sale_data(&lock, &[sale_in, other_in], &[sale_out, other_out]), Ok(()));
+	assert_eq!(validate_token_sale_data(&lock, &[sale_in, other_in], &[other_out, sale_out]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_data(&lock, &[sale_in, other_in], &[sale_out]), Err(Error::InvalidStructure));
+	assert_eq!(validate_token_sale_data(&lock, &[sale_in], &[sale_out, other_out]), Err(Error::InvalidStructure));
+}
+
+#[test]
+fn test_core_validate()
+{
+	use token_sale_core::validate;
+
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, 2);
+	let lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &args);
+	let type_ = create_core_script(&CORE_SUDT_CODE_HASH, &CORE_OWNER_LOCK_HASH);
+	let data_in = 10u128.to_le_bytes();
+	let data_out = 7u128.to_le_bytes();
+	let data_under = 8u128.to_le_bytes();
+	let capacity_cell = create_core_cell(1_000, create_core_script(&CORE_SUDT_CODE_HASH, &[]), &CORE_OTHER_LOCK_HASH, None, &[]);
+	let owner_cell = create_core_cell(1_000, create_core_script(&CORE_SUDT_CODE_HASH, &[]), &CORE_OWNER_LOCK_HASH, None, &[]);
+	let sale_in = create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_in);
+	let sale_out = create_core_cell(106, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_out);
+	let sale_out_under = create_core_cell(106, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_under);
+
+	// A valid purchase.
+	assert_eq!(validate(&lock, &[capacity_cell, sale_in], &[sale_out]), Ok(()));
+	assert_eq!(validate(&lock, &[capacity_cell, sale_in], &[sale_out_under]), Err(Error::ExchangeRate));
+
+	// Owner mode unlocks unconditionally.
+	assert_eq!(validate(&lock, &[owner_cell, sale_in], &[]), Ok(()));
+
+	// The args must be at least 40 bytes.
+	let short_args = &args[0..39];
+	let short_lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, short_args);
+	assert_eq!(validate(&short_lock, &[owner_cell], &[]), Err(Error::ArgsLen));
+	assert_eq!(validate(&lock, &[owner_cell], &[]), Ok(()));
+}
+
+#[test]
+fn test_core_validate_xudt_configurations()
+{
+	use token_sale_core::validate;
+
+	// Constants
+	const XUDT_FLAGS_EXTENSION_SCRIPTS: u32 = 1; // The extension scripts are stored in the args.
+	const XUDT_FLAGS_EXTENSION_SCRIPTS_HASH: u32 = 2; // The hash of the extension scripts is stored in the args.
+	const XUDT_FLAGS_OWNER_MODE_INPUT_TYPE: u32 = 0x8000_0000; // Owner mode is also enabled by an input Type Script.
+
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, 2);
+	let lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &args);
+	// A ScriptVec containing a single extension script, serialized as a molecule dynvec.
+	let extension_script = Script::new_builder().code_hash(CellOutput::calc_data_hash(&ALWAYS_SUCCESS)).build().as_bytes();
+	let mut extension_scripts = ((8 + extension_script.len()) as u32).to_le_bytes().to_vec();
+	extension_scripts.append(&mut 8u32.to_le_bytes().to_vec());
+	extension_scripts.extend_from_slice(&extension_script);
+	let configurations =
+	[
+		CORE_OWNER_LOCK_HASH.to_vec(),
+		create_xudt_args(&CORE_OWNER_LOCK_HASH, 0, &[]),
+		create_xudt_args(&CORE_OWNER_LOCK_HASH, XUDT_FLAGS_EXTENSION_SCRIPTS, &extension_scripts),
+		create_xudt_args(&CORE_OWNER_LOCK_HASH, XUDT_FLAGS_EXTENSION_SCRIPTS_HASH, &blake2b_256(&extension_scripts)[0..20]),
+		create_xudt_args(&CORE_OWNER_LOCK_HASH, XUDT_FLAGS_EXTENSION_SCRIPTS | XUDT_FLAGS_OWNER_MODE_INPUT_TYPE, &extension_scripts),
+	];
+	let xudt_data = [0xCCu8; 12];
+	let data_in = create_xudt_data(10, &xudt_data);
+	let data_out = create_xudt_data(7, &xudt_data);
+	let data_altered = create_xudt_data(7, &xudt_data[1..]);
+	let capacity_cell = create_core_cell(1_000, create_core_script(&CORE_SUDT_CODE_HASH, &[]), &CORE_OTHER_LOCK_HASH, None, &[]);
+
+	for (i, type_args) in configurations.iter().enumerate()
+	{
+		let type_ = create_core_script(&CORE_SUDT_CODE_HASH, type_args);
+		let sale_in = create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_in);
+		let sale_out = create_core_cell(106, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_out);
+		let sale_out_altered = create_core_cell(106, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_altered);
+
+		// A purchase which leaves the configuration and xUDT data unchanged.
+		assert_eq!(validate(&lock, &[capacity_cell, sale_in], &[sale_out]), Ok(()));
+		assert_eq!(validate(&lock, &[capacity_cell, sale_in], &[sale_out_altered]), Err(Error::InvalidStructure));
+
+		// Every other configuration is a different token.
+		for other_type_args in configurations.iter().enumerate().filter(|(j, _)| *j != i).map(|(_, args)| args)
+		{
+			let other_type = create_core_script(&CORE_SUDT_CODE_HASH, other_type_args);
+			let other_sale_out = create_core_cell(106, lock, &CORE_OTHER_LOCK_HASH, Some(other_type), &data_out);
+			assert_eq!(validate(&lock, &[capacity_cell, sale_in], &[other_sale_out]), Err(Error::InvalidStructure));
+		}
+	}
+}
+
+#[test]
+fn test_core_validate_transaction()
+{
+	use token_sale_core::validate_transaction;
+
+	let data_hash_type: u8 = ScriptHashType::Data.into();
+	let type_hash_type: u8 = ScriptHashType::Type.into();
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, 2);
+	let lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &args);
+	let type_ = create_core_script(&CORE_SUDT_CODE_HASH, &CORE_OWNER_LOCK_HASH);
+	let data_in = 10u128.to_le_bytes();
+	let data_out = 7u128.to_le_bytes();
+	let sale_in = create_core_cell(100, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_in);
+	let sale_out = create_core_cell(106, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_out);
+	let sale_out_under = create_core_cell(105, lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_out);
+	let other_args = create_core_args(&CORE_OWNER_LOCK_HASH, 3);
+	let other_lock = create_core_script(&CORE_TOKEN_SALE_CODE_HASH, &other_args);
+	let other_sale_in = create_core_cell(100, other_lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_in);
+	let other_sale_out = create_core_cell(109, other_lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_out);
+	let other_sale_out_under = create_core_cell(106, other_lock, &CORE_OTHER_LOCK_HASH, Some(type_), &data_out);
+
+	// Every Token Sale script group is validated.
+	assert_eq!(validate_transaction(&CORE_TOKEN_SALE_CODE_HASH, data_hash_type, &[sale_in], &[sale_out]), Ok(()));
+	assert_eq!(validate_transaction(&CORE_TOKEN_SALE_CODE_HASH, data_hash_type, &[sale_in], &[sale_out_under]), Err(Error::ExchangeRate));
+	assert_eq!(validate_transaction(&CORE_TOKEN_SALE_CODE_HASH, data_hash_type, &[sale_in, other_sale_in], &[sale_out, other_sale_out]), Ok(()));
+	assert_eq!(validate_transaction(&CORE_TOKEN_SALE_CODE_HASH, data_hash_type, &[sale_in, other_sale_in], &[sale_out, other_sale_out_under]), Err(Error::ExchangeRate));
+
+	// Cells using a different code hash or hash type are not Token Sale Cells.
+	assert_eq!(validate_transaction(&CORE_SUDT_CODE_HASH, data_hash_type, &[sale_in], &[sale_out_under]), Ok(()));
+	assert_eq!(validate_transaction(&CORE_TOKEN_SALE_CODE_HASH, type_hash_type, &[sale_in], &[sale_out_under]), Ok(()));
+}
+
+#[test]
+#[ignore]
+fn bench_transaction_shapes()
+{
+	// Each shape is the number of Token Sale Cells and the number of unrelated Cells on each side.
+	let shapes = [(1, 0), (1, 10), (1, 100), (2, 0), (4, 0), (8, 0), (8, 10)];
+
+	println!("{:>6} {:>7} {:>12} {:>16}", "sales", "filler", "cycles", "cycles per sale");
+	for (sales, filler_cells) in shapes.iter()
+	{
+		let cycles = create_purchase_scenario(*sales, *filler_cells).expect_pass();
+		println!("{:>6} {:>7} {:>12} {:>16}", sales, filler_cells, cycles, cycles / *sales as u64);
+	}
+}
+
+/// Create a randomized purchase from many Token Sale Cells, mixed with SUDT transfers and unrelated Cells in a random
+/// order. If an overpaid sale is specified, that Token Sale Cell receives one Shannon too many.
+fn create_random_multi_group_scenario(seed: u64, overpaid_sale: Option<u32>) -> Scenario
+{
+	let mut rng = XorShift::new(seed);
+	let sales = rng.range(2, 8) as u32;
+	let transfers = rng.range(0, 4);
+	let filler_cells = rng.range(0, 4);
+
+	// Buy a random amount of tokens at a random cost from each Token Sale Cell.
+	let mut scenario = Scenario::new();
+	let mut tokens_bought = 0;
+	let mut capacity_paid = 0;
+	for id in 0..sales
+	{
+		let cost = rng.range(1, 1_000);
+		let tokens = rng.range(1, 10);
+		let capacity = tokens * cost + if overpaid_sale == Some(id) { 1 } else { 0 };
+		scenario = scenario
+			.sale_in(1_000, 100, cost, id)
+			.sale_out(1_000 + capacity, 100 - tokens as u128, cost, id);
+		tokens_bought += tokens as u128;
+		capacity_paid += capacity;
+	}
+	scenario = scenario
+		.capacity_in(100 + capacity_paid)
+		.sudt_out(100, tokens_bought);
+
+	// Add plain SUDT transfers and unrelated Cells.
+	for _ in 0..transfers
+	{
+		let tokens = rng.range(1, 1_000) as u128;
+		scenario = scenario
+			.sudt_in(100, tokens)
+			.sudt_out(100, tokens);
+	}
+	for _ in 0..filler_cells
+	{
+		scenario = scenario
+			.capacity_in(100)
+			.capacity_out(100);
+	}
+
+	scenario.shuffle(&mut rng)
+}
+
+#[test]
+fn test_random_multi_group()
+{
+	for seed in 0..16
+	{
+		println!("Seed: {}", seed);
 
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
+		// Every Token Sale script group is valid.
+		let cycles = create_random_multi_group_scenario(seed, None).expect_pass();
+		println!("Cycles: {}", cycles);
 
-	// Execute the transaction.
-	let _cycles = context.verify_tx(&tx, MAX_CYCLES).expect("pass verification");
-	// println!("Cycles: {}", cycles);
+		// Exactly one Token Sale script group is invalid. There are always at least two.
+		create_random_multi_group_scenario(seed, Some((seed % 2) as u32)).expect_err(Error::ExchangeRate);
+	}
 }
 
 #[test]
-fn test_multiple_separate_token_sale_cells_invalid()
+#[ignore]
+fn test_xudt_buy_with_extension_data()
+{
+	require_token_script("xudt");
+
+	// Every xUDT Cell has explicit xUDT flags and carries the same extension data after the token amount.
+	Scenario::new()
+		.token_script("xudt")
+		.token_type_args(create_xudt_args(&[0u8; 32], 0, &[]).into())
+		.token_extension_data(Bytes::from(vec!(0xAAu8; 4_096)))
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, 100, 0)
+		.sudt_in(100, 10)
+		.capacity_out(900)
+		.sale_out(1_100, 99, 100, 0)
+		.sudt_out(100, 11)
+		.expect_pass();
+}
+
+/// Replace the args of the Type Script of a Cell.
+fn set_type_args(output: CellOutput, args: &[u8]) -> CellOutput
+{
+	let type_script = output.type_().to_opt().expect("type script").as_builder().args(Bytes::from(args.to_vec()).pack()).build();
+	output.as_builder().type_(Some(type_script).pack()).build()
+}
+
+#[test]
+#[ignore]
+fn test_xudt_configurations()
+{
+	require_token_script("xudt");
+
+	for extension in [vec!(), vec!(0xCCu8; 12)].iter()
+	{
+		// Get defaults.
+		let (mut context, tx, resources) = build_context_and_resources("xudt");
+		let lock_hash_owner: [u8; 32] = resources.scripts.get("lock-1").unwrap().calc_script_hash().unpack();
+		let xudt_args = create_xudt_args(&[0u8; 32], 0, &[]);
+		let xudt_owner_args = create_xudt_args(&lock_hash_owner, 0, &[]);
+
+		// A purchase from a Token Sale Cell with explicit xUDT flags, which is rejected if the xUDT data is altered.
+		let (sale_in, sale_in_data) = create_output_token_sale_xudt_cell(&mut context, &resources, 1_000, 100, extension, 100, 0, false, false);
+		let sale_in = CellInput::new_builder().previous_output(context.create_cell(set_type_args(sale_in, &xudt_args), sale_in_data)).build();
+		let capacity_in = create_input_capacity_cell(&mut context, &resources, 1_000);
+		for (sale_out_extension, expected) in [(extension.clone(), Ok(())), (vec!(0xDDu8; 12), Err(Error::InvalidStructure))].iter()
+		{
+			let (sale_out, sale_out_data) = create_output_token_sale_xudt_cell(&mut context, &resources, 1_100, 99, sale_out_extension, 100, 0, false, false);
+			let (buyer_out, buyer_out_data) = create_output_xudt_cell(&mut context, &resources, 100, 1, &[], false);
+			let buy_tx = tx.clone()
+				.input(capacity_in.clone())
+				.input(sale_in.clone())
+				.output(set_type_args(sale_out, &xudt_args))
+				.output_data(sale_out_data.pack())
+				.output(set_type_args(buyer_out, &xudt_args))
+				.output_data(buyer_out_data.pack())
+				.build();
+			let buy_tx = context.complete_tx(buy_tx);
+			match expected
+			{
+				Ok(()) =>
+				{
+					assert_eq!(validate_transaction_host(&context, &resources, &buy_tx), Ok(()));
+					context.verify_tx(&buy_tx, max_cycles()).expect("pass verification");
+				},
+				Err(error) => expect_tx_err(&mut context, &resources, &buy_tx, *error),
+			}
+		}
+
+		// The owner mints tokens into a Token Sale Cell using xUDT owner mode.
+		let (owner_sale_in, owner_sale_in_data) = create_output_token_sale_xudt_cell(&mut context, &resources, 1_000, 100, extension, 100, 0, true, true);
+		let owner_sale_in = context.create_cell(set_type_args(owner_sale_in, &xudt_owner_args), owner_sale_in_data);
+		let (owner_sale_out, owner_sale_out_data) = create_output_token_sale_xudt_cell(&mut context, &resources, 1_000, 200, extension, 100, 0, true, true);
+		let refill_tx = tx.clone()
+			.input(create_input_capacity_cell(&mut context, &resources, 1_000))
+			.input(CellInput::new_builder().previous_output(owner_sale_in).build())
+			.output(set_type_args(owner_sale_out, &xudt_owner_args))
+			.output_data(owner_sale_out_data.pack())
+			.build();
+		let refill_tx = context.complete_tx(refill_tx);
+		assert_eq!(validate_transaction_host(&context, &resources, &refill_tx), Ok(()));
+		context.verify_tx(&refill_tx, max_cycles()).expect("pass verification");
+	}
+}
+
+#[test]
+fn test_max_size_transaction()
 {
 	// Constants
-	const TOKEN_SALE_OWNER_MODE: bool = false;
-	const SUDT_OWNER_MODE: bool = false;
+	const FILLER_CELLS: u32 = 150;
+
+	// A single purchase among hundreds of unrelated Cells, padded to the largest transaction which fits in a block.
+	let (context, resources, tx) = create_purchase_scenario(1, FILLER_CELLS).build();
+	let tx = pad_transaction(&tx, MAX_TRANSACTION_BYTES);
+	assert_eq!(tx.data().serialized_size_in_block() as u64, MAX_TRANSACTION_BYTES);
+	assert_eq!(validate_transaction_host(&context, &resources, &tx), Ok(()));
+	let cycles = context.verify_tx(&tx, max_cycles()).expect("pass verification");
+	println!("Cycles: {}", cycles);
+
+	// An invalid purchase among hundreds of unrelated Cells.
+	let mut scenario = Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_out(850)
+		.sale_out(1_050, 99, 100, 0)
+		.sudt_out(100, 1);
+	for _ in 0..FILLER_CELLS
+	{
+		scenario = scenario
+			.capacity_in(100)
+			.capacity_out(100);
+	}
+	let (mut context, resources, tx) = scenario.build();
+	let tx = pad_transaction(&tx, MAX_TRANSACTION_BYTES);
+	expect_tx_err(&mut context, &resources, &tx, Error::ExchangeRate);
+}
 
-	// Get defaults.
-	let (mut context, tx, resources) = build_default_context_and_resources();
-	
-	// Prepare inputs.
-	let mut inputs = vec!();
-	let input = create_input_capacity_cell(&mut context, &resources, 102);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 100, 1, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	let input = create_input_token_sale_cell(&mut context, &resources, 100, 100, 1, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	inputs.push(input);
-	
-	// Prepare outputs.
-	let mut outputs = vec!();
-	let mut outputs_data = vec!();
-	let (output, output_data) = create_output_sudt_cell(&mut context, &resources, 100, 2, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 101, 99, 1, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-	let (output, output_data) = create_output_token_sale_cell(&mut context, &resources, 101, 99, 1, 0, TOKEN_SALE_OWNER_MODE, SUDT_OWNER_MODE);
-	outputs.push(output);
-	outputs_data.push(output_data);
-
-	// Populate the transaction, build, and complete.
-	let tx = tx.inputs(inputs).outputs(outputs).outputs_data(outputs_data.pack()).build();
-	let tx = context.complete_tx(tx);
-
-	// Execute the transaction.
-	let err = context.verify_tx(&tx, MAX_CYCLES).unwrap_err();
-	assert_error_eq!(err, ScriptError::ValidationFailure(ERROR_STRUCTURE));
+/// Pad a completed transaction to the specified serialized size by spreading data across its capacity output Cells.
+fn pad_transaction(tx: &TransactionView, size: u64) -> TransactionView
+{
+	let padding = size - tx.data().serialized_size_in_block() as u64;
+	let fillers: Vec<usize> = tx.outputs().into_iter().enumerate().filter(|(_, output)| output.type_().is_none()).map(|(index, _)| index).collect();
+	let mut tx = tx.clone();
+	for (i, index) in fillers.iter().enumerate()
+	{
+		let len = padding / fillers.len() as u64 + if (i as u64) < padding % fillers.len() as u64 { 1 } else { 0 };
+		let output = tx.outputs().get(*index).unwrap();
+		tx = replace_output(&tx, *index, output, Bytes::from(vec![0u8; len as usize]));
+	}
+
+	tx
+}
+
+/// A step in a sale lifecycle.
+#[derive(Clone, Copy, Debug)]
+enum LifecycleStep
+{
+	Create { capacity: u64, tokens: u128, cost: u64 },
+	Purchase { tokens: u128 },
+	Refill { tokens: u128 },
+	Reprice { cost: u64 },
+	Close,
+}
+
+/// Generate a reproducible randomized sale lifecycle from a seed.
+fn generate_sale_lifecycle(seed: u64) -> Vec<LifecycleStep>
+{
+	let mut rng = XorShift::new(seed);
+	let mut steps = vec!();
+
+	// Create the Token Sale Cell.
+	let mut tokens = rng.range(10, 1_000) as u128;
+	steps.push(LifecycleStep::Create { capacity: rng.range(1_000, 100_000), tokens, cost: rng.range(1, 1_000) });
+
+	// Purchase tokens, always leaving at least one.
+	for _ in 0..rng.range(1, 5)
+	{
+		if tokens == 1
+		{
+			break;
+		}
+
+		let purchase = rng.range(1, (tokens as u64 - 1).min(10)) as u128;
+		steps.push(LifecycleStep::Purchase { tokens: purchase });
+		tokens -= purchase;
+	}
+
+	// Refill, reprice, then close the Token Sale Cell.
+	steps.push(LifecycleStep::Refill { tokens: rng.range(1, 1_000) as u128 });
+	steps.push(LifecycleStep::Reprice { cost: rng.range(1, 1_000) });
+	steps.push(LifecycleStep::Close);
+
+	steps
+}
+
+/// Execute a sale lifecycle with one transaction per step, carrying the state of the Token Sale Cell between them.
+fn execute_sale_lifecycle(steps: &[LifecycleStep])
+{
+	let (mut sale_capacity, mut sale_tokens, mut sale_cost) = (0, 0, 0);
+
+	for step in steps
+	{
+		println!("Step: {:?}", step);
+
+		match *step
+		{
+			LifecycleStep::Create { capacity, tokens, cost } =>
+			{
+				Scenario::new()
+					.capacity_in(capacity)
+					.sudt_in(100, tokens)
+					.capacity_out(100)
+					.sale_out(capacity, tokens, cost, 0)
+					.expect_pass();
+				sale_capacity = capacity;
+				sale_tokens = tokens;
+				sale_cost = cost;
+			},
+			LifecycleStep::Purchase { tokens } =>
+			{
+				// Paying one Shannon too little must fail before paying the correct amount.
+				let payment = tokens as u64 * sale_cost;
+				for (paid, expect_pass) in [(payment - 1, false), (payment, true)].iter()
+				{
+					let scenario = Scenario::new()
+						.capacity_in(payment + 200)
+						.sale_in(sale_capacity, sale_tokens, sale_cost, 0)
+						.capacity_out(200 + payment - paid)
+						.sale_out(sale_capacity + paid, sale_tokens - tokens, sale_cost, 0)
+						.sudt_out(100, tokens);
+					if *expect_pass
+					{
+						scenario.expect_pass();
+					}
+					else
+					{
+						scenario.expect_err(if *paid == 0 { Error::AmountCkbytes } else { Error::ExchangeRate });
+					}
+				}
+				sale_capacity += payment;
+				sale_tokens -= tokens;
+			},
+			LifecycleStep::Refill { tokens } =>
+			{
+				Scenario::new()
+					.token_sale_owner_mode()
+					.sale_in(sale_capacity, sale_tokens, sale_cost, 0)
+					.sudt_in(100, tokens)
+					.sale_out(sale_capacity, sale_tokens + tokens, sale_cost, 0)
+					.capacity_out(100)
+					.expect_pass();
+				sale_tokens += tokens;
+			},
+			LifecycleStep::Reprice { cost } =>
+			{
+				Scenario::new()
+					.token_sale_owner_mode()
+					.sale_in(sale_capacity, sale_tokens, sale_cost, 0)
+					.capacity_in(100)
+					.sale_out(sale_capacity, sale_tokens, cost, 0)
+					.capacity_out(100)
+					.expect_pass();
+				sale_cost = cost;
+			},
+			LifecycleStep::Close =>
+			{
+				Scenario::new()
+					.token_sale_owner_mode()
+					.sale_in(sale_capacity, sale_tokens, sale_cost, 0)
+					.capacity_in(100)
+					.capacity_out(sale_capacity)
+					.sudt_out(100, sale_tokens)
+					.expect_pass();
+			},
+		}
+	}
+}
+
+#[test]
+fn test_seeded_sale_lifecycles()
+{
+	// A failing lifecycle can be replayed by setting the seed.
+	let seeds: Vec<u64> = match env::var(SCENARIO_SEED_ENV_VAR)
+	{
+		Ok(seed) => vec!(seed.parse().expect("scenario seed")),
+		Err(_) => (0..DEFAULT_SCENARIO_SEEDS).collect(),
+	};
+
+	for seed in seeds
+	{
+		println!("Seed: {} (Replay with {}={})", seed, SCENARIO_SEED_ENV_VAR, seed);
+		execute_sale_lifecycle(&generate_sale_lifecycle(seed));
+	}
+}
+
+#[test]
+fn test_epoch_and_since_utilities()
+{
+	// Advancing an epoch rolls over into the next epoch.
+	let epoch = EpochNumberWithFraction::new(1, 1_798, 1_800);
+	assert_eq!(advance_epoch(epoch, 1), EpochNumberWithFraction::new(1, 1_799, 1_800));
+	assert_eq!(advance_epoch(epoch, 2), EpochNumberWithFraction::new(2, 0, 1_800));
+	assert_eq!(advance_epoch(epoch, 1_802), EpochNumberWithFraction::new(3, 0, 1_800));
+
+	// The since flags occupy the highest byte.
+	assert_eq!(create_since_block_number(100, false), 100);
+	assert_eq!(create_since_block_number(100, true), 0x8000_0000_0000_0064);
+	assert_eq!(create_since_epoch(EpochNumberWithFraction::new(1, 0, 1), false), 0x2000_0100_0000_0001);
+	assert_eq!(create_since_timestamp(1_600_000_000, true), 0xC000_0000_5F5E_1000);
+}
+
+#[test]
+fn test_buy_with_header_deps_and_since()
+{
+	let epoch = EpochNumberWithFraction::new(10, 0, 1_800);
+	let created = create_header(1_000, epoch, 1_600_000_000_000);
+	let current = create_header(2_000, advance_epoch(epoch, 1_000), 1_600_010_000_000);
+
+	// The Token Sale Lock Script does not restrict header deps or since values without a sale start.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, 100, 0)
+		.in_block(created)
+		.since(create_since_epoch(EpochNumberWithFraction::new(0, 1, 1_800), true))
+		.header_dep(current)
+		.capacity_out(800)
+		.sale_out(1_100, 99, 100, 0)
+		.sudt_out(100, 1)
+		.expect_pass();
+}
+
+/// Create the Token Sale Lock args of a Token Sale Cell with a sale start at epoch 10.
+fn create_sale_start_args(owner_lock_hash: &[u8; 32]) -> Bytes
+{
+	let start = EpochNumberWithFraction::new(10, 0, 1).full_value().to_le_bytes();
+
+	create_flagged_args(owner_lock_hash, 100, 0, token_sale_core::FLAG_SALE_START, &start).into()
+}
+
+#[test]
+fn test_buy_after_sale_start()
+{
+	let args = create_sale_start_args(&[0u8; 32]);
+	let epoch_since = |number, index, length| create_since_epoch(EpochNumberWithFraction::new(number, index, length), false);
+
+	for since in [epoch_since(10, 0, 1), epoch_since(1_000, 0, 1)].iter()
+	{
+		Scenario::new()
+			.capacity_in(1_000)
+			.sale_args_in(1_000, 100, args.clone())
+			.since(*since)
+			.capacity_out(900)
+			.sale_args_out(1_100, 99, args.clone())
+			.sudt_out(100, 1)
+			.expect_pass();
+	}
+}
+
+#[test]
+fn test_buy_before_sale_start()
+{
+	let args = create_sale_start_args(&[0u8; 32]);
+	let epoch_since = |number, index, length| create_since_epoch(EpochNumberWithFraction::new(number, index, length), false);
+
+	// Before the start, without a since, or with a since which is not an absolute epoch.
+	for since in [epoch_since(9, 1_799, 1_800), 0, create_since_epoch(EpochNumberWithFraction::new(15, 0, 1), true), create_since_block_number(15, false)].iter()
+	{
+		Scenario::new()
+			.capacity_in(1_000)
+			.sale_args_in(1_000, 100, args.clone())
+			.since(*since)
+			.capacity_out(900)
+			.sale_args_out(1_100, 99, args.clone())
+			.sudt_out(100, 1)
+			.expect_err(Error::SaleWindow);
+	}
+}
+
+#[test]
+fn test_sale_start_owner_mode()
+{
+	// The owner can always reclaim the Token Sale Cell.
+	let args = create_sale_start_args(&create_scenario_owner_lock_hash());
+	Scenario::new()
+		.capacity_in(100)
+		.sale_args_in(1_000, 100, args)
+		.capacity_out(1_000)
+		.sudt_out(100, 100)
+		.expect_pass();
+}
+
+/// Create the Token Sale Lock args of a Token Sale Cell with a whitelist of the specified lock hashes. Returns the args
+/// and the proof of each lock hash.
+fn create_whitelist_args(owner_lock_hash: &[u8; 32], whitelist: &[[u8; 32]]) -> (Bytes, Vec<Bytes>)
+{
+	let proofs: Vec<Bytes> = (0..whitelist.len()).map(|i| create_merkle_proof(whitelist, i).1.into()).collect();
+	let (root, _proof) = create_merkle_proof(whitelist, 0);
+	let args = create_flagged_args(owner_lock_hash, 100, 0, token_sale_core::FLAG_WHITELIST, &root);
+
+	(args.into(), proofs)
+}
+
+/// Create the witness lock which proves that the lock hash of the input Cell at the specified index is whitelisted.
+fn create_whitelist_witness_lock(index: u32, proof: &[u8]) -> Bytes
+{
+	let mut witness_lock = index.to_le_bytes().to_vec();
+	witness_lock.extend_from_slice(proof);
+
+	witness_lock.into()
+}
+
+#[test]
+fn test_buy_whitelisted()
+{
+	// The capacity Cell of the buyer uses the whitelisted lock, which receives the tokens.
+	let whitelist = [[5u8; 32], create_scenario_owner_lock_hash(), [6u8; 32]];
+	let (args, proofs) = create_whitelist_args(&[0u8; 32], &whitelist);
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.witness_lock(create_whitelist_witness_lock(0, &proofs[1]))
+		.capacity_out(900)
+		.sale_args_out(1_100, 99, args)
+		.sudt_out(100, 1)
+		.expect_pass();
+}
+
+#[test]
+fn test_buy_not_whitelisted()
+{
+	// The proof of another whitelisted lock hash, a truncated proof, the index of the Token Sale Cell, an index out of
+	// bounds, or no proof.
+	let whitelist = [[5u8; 32], create_scenario_owner_lock_hash(), [6u8; 32]];
+	let (args, proofs) = create_whitelist_args(&[0u8; 32], &whitelist);
+	let witness_locks =
+	[
+		Some(create_whitelist_witness_lock(0, &proofs[0])),
+		Some(create_whitelist_witness_lock(0, &proofs[1][1..])),
+		Some(create_whitelist_witness_lock(1, &proofs[1])),
+		Some(create_whitelist_witness_lock(2, &proofs[1])),
+		Some(proofs[1].clone()),
+		Some(Bytes::new()),
+		None,
+	];
+	for witness_lock in witness_locks.iter()
+	{
+		let mut scenario = Scenario::new()
+			.capacity_in(1_000)
+			.sale_args_in(1_000, 100, args.clone());
+		if let Some(witness_lock) = witness_lock
+		{
+			scenario = scenario.witness_lock(witness_lock.clone());
+		}
+		scenario
+			.capacity_out(900)
+			.sale_args_out(1_100, 99, args.clone())
+			.sudt_out(100, 1)
+			.expect_err(Error::Whitelist);
+	}
+
+	// A whitelist which does not include the buyer.
+	let (args, proofs) = create_whitelist_args(&[0u8; 32], &[[5u8; 32], [6u8; 32]]);
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.witness_lock(create_whitelist_witness_lock(0, &proofs[0]))
+		.capacity_out(900)
+		.sale_args_out(1_100, 99, args)
+		.sudt_out(100, 1)
+		.expect_err(Error::Whitelist);
+}
+
+#[test]
+fn test_whitelist_tokens_to_other_lock()
+{
+	// Constants
+	const SUDT_OUTPUT_INDEX: usize = 2; // The index of the output SUDT Cell.
+
+	// The buyer proves the whitelisted lock, but sends the tokens to a lock which is not whitelisted.
+	let whitelist = [[5u8; 32], create_scenario_owner_lock_hash(), [6u8; 32]];
+	let (args, proofs) = create_whitelist_args(&[0u8; 32], &whitelist);
+	let (mut context, resources, tx) = Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.witness_lock(create_whitelist_witness_lock(0, &proofs[1]))
+		.capacity_out(900)
+		.sale_args_out(1_100, 99, args)
+		.sudt_out(100, 1)
+		.build();
+	let output = tx.outputs().get(SUDT_OUTPUT_INDEX).unwrap();
+	let output_data = tx.outputs_data().get(SUDT_OUTPUT_INDEX).unwrap().unpack();
+	let other_lock = output.lock().as_builder().args(Bytes::from(vec!(0u8, 2)).pack()).build();
+	let tx = replace_output(&tx, SUDT_OUTPUT_INDEX, output.as_builder().lock(other_lock).build(), output_data);
+	expect_tx_err(&mut context, &resources, &tx, Error::Whitelist);
+}
+
+#[test]
+fn test_whitelist_owner_mode()
+{
+	// The owner does not need to be whitelisted.
+	let (args, _proofs) = create_whitelist_args(&create_scenario_owner_lock_hash(), &[[5u8; 32], [6u8; 32]]);
+	Scenario::new()
+		.capacity_in(100)
+		.sale_args_in(1_000, 100, args)
+		.capacity_out(1_000)
+		.sudt_out(100, 100)
+		.expect_pass();
+}
+
+#[test]
+#[ignore]
+fn test_whitelist_with_extension_witness_data()
+{
+	require_token_script("xudt");
+
+	let (_context, _tx, resources) = build_default_context_and_resources();
+	let mut token_scripts: Vec<&String> = resources.token_scripts.keys().collect();
+	token_scripts.sort();
+
+	let whitelist = [[5u8; 32], create_scenario_owner_lock_hash(), [6u8; 32]];
+	let (args, proofs) = create_whitelist_args(&[0u8; 32], &whitelist);
+	for token_script in token_scripts
+	{
+		let (context, resources, tx) = Scenario::new()
+			.token_script(token_script)
+			.capacity_in(1_000)
+			.sale_args_in(1_000, 100, args.clone())
+			.witness_lock(create_whitelist_witness_lock(0, &proofs[1]))
+			.capacity_out(900)
+			.sale_args_out(1_100, 99, args.clone())
+			.sudt_out(100, 1)
+			.build();
+
+		// The xUDT extension data in the other fields of the WitnessArgs is ignored by the Token Sale Lock Script.
+		let witness = WitnessArgs::new_builder()
+			.lock(Some(create_whitelist_witness_lock(0, &proofs[1])).pack())
+			.input_type(Some(Bytes::from(vec!(0xAAu8; 64))).pack())
+			.output_type(Some(Bytes::from(vec!(0xBBu8; 64))).pack())
+			.build();
+		let mut witnesses: Vec<Bytes> = tx.witnesses().into_iter().map(|witness| witness.unpack()).collect();
+		witnesses[1] = witness.as_bytes();
+		let tx = tx.as_advanced_builder().set_witnesses(witnesses.into_iter().map(|witness| witness.pack()).collect()).build();
+
+		assert_eq!(validate_transaction_host(&context, &resources, &tx), Ok(()));
+		context.verify_tx(&tx, max_cycles()).expect("pass verification");
+	}
+}
+
+#[test]
+#[ignore]
+fn test_whitelist_with_anyone_can_pay_lock()
+{
+	// Constants
+	const ACP_KEY_SEED: u64 = 1; // The seed used to generate the key of the whitelisted user.
+	const ACP_INPUT_INDEX: u32 = 2; // The index of the input ACP Cell.
+
+	// The buyer is not whitelisted, but the ACP lock of a whitelisted user does not require a signature from its holder.
+	let acp_lock_hash: [u8; 32] = create_anyone_can_pay_lock(&load_deps_binary(ANYONE_CAN_PAY_FILE), ACP_KEY_SEED).calc_script_hash().unpack();
+	let whitelist = [[5u8; 32], acp_lock_hash, [6u8; 32]];
+	let (args, proofs) = create_whitelist_args(&[0u8; 32], &whitelist);
+	let (mut context, resources, tx) = Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.witness_lock(create_whitelist_witness_lock(ACP_INPUT_INDEX, &proofs[1]))
+		.capacity_out(900)
+		.sale_args_out(1_100, 99, args)
+		.sudt_out(100, 1)
+		.build();
+
+	// The buyer includes the ACP Cell of the whitelisted user without a signature by paying it one Shannon.
+	let (acp_lock, acp_deps) = deploy_anyone_can_pay_lock(&mut context, ACP_KEY_SEED);
+	let acp_cell = CellOutput::new_builder().capacity(1_000u64.pack()).lock(acp_lock).build();
+	let acp_input = CellInput::new_builder().previous_output(context.create_cell(acp_cell.clone(), Bytes::new())).build();
+	let tx = tx.as_advanced_builder()
+		.cell_deps(acp_deps)
+		.input(acp_input)
+		.witness(Bytes::new().pack())
+		.output(acp_cell.as_builder().capacity(1_001u64.pack()).build())
+		.output_data(Bytes::new().pack())
+		.build();
+
+	// The proof is bound to the ACP Cell, which does not receive the tokens.
+	expect_tx_err(&mut context, &resources, &tx, Error::Whitelist);
+}
+
+/// Create the Token Sale Lock args of a Token Sale Cell with a cost of 100 and a buy-back cost of 90.
+fn create_buy_back_args(owner_lock_hash: &[u8; 32]) -> Bytes
+{
+	create_flagged_args(owner_lock_hash, 100, 0, token_sale_core::FLAG_BUY_BACK, &90u64.to_le_bytes()).into()
+}
+
+#[test]
+fn test_buy_back()
+{
+	let args = create_buy_back_args(&[0u8; 32]);
+
+	// Tokens are sold back at the buy-back cost.
+	Scenario::new()
+		.sudt_in(100, 2)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_out(820, 102, args.clone())
+		.capacity_out(280)
+		.expect_pass();
+
+	// Tokens can still be bought at the cost.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(900)
+		.sale_args_out(1_100, 99, args)
+		.sudt_out(100, 1)
+		.expect_pass();
+}
+
+#[test]
+fn test_buy_back_invalid()
+{
+	let args = create_buy_back_args(&[0u8; 32]);
+
+	// Tokens sold back at the cost instead of the buy-back cost.
+	Scenario::new()
+		.sudt_in(100, 1)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_out(900, 101, args.clone())
+		.capacity_out(200)
+		.expect_err(Error::ExchangeRate);
+
+	// Capacity taken without returning tokens.
+	Scenario::new()
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_out(910, 100, args.clone())
+		.capacity_out(90)
+		.expect_err(Error::AmountSudt);
+
+	// A buy-back cost of 0.
+	let args: Bytes = create_flagged_args(&[0u8; 32], 100, 0, token_sale_core::FLAG_BUY_BACK, &0u64.to_le_bytes()).into();
+	Scenario::new()
+		.sudt_in(100, 1)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_out(1_000, 101, args)
+		.capacity_out(100)
+		.expect_err(Error::InvalidCost);
+
+	// A buy-back cost above the cost, which would allow tokens to be bought and sold back at a profit.
+	let args: Bytes = create_flagged_args(&[0u8; 32], 100, 0, token_sale_core::FLAG_BUY_BACK, &101u64.to_le_bytes()).into();
+	Scenario::new()
+		.sudt_in(100, 1)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_out(899, 101, args)
+		.capacity_out(201)
+		.expect_err(Error::InvalidCost);
+}
+
+#[test]
+fn test_buy_back_owner_mode()
+{
+	// The owner can withdraw capacity without returning tokens.
+	let args = create_buy_back_args(&create_scenario_owner_lock_hash());
+	Scenario::new()
+		.capacity_in(100)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_out(500, 100, args)
+		.capacity_out(600)
+		.expect_pass();
+}
+
+/// Create args for a Token Sale Cell which sells the denominator number of tokens at the specified cost.
+fn create_price_denominator_args(owner_lock_hash: &[u8; 32], cost: u64, price_denominator: u64) -> Bytes
+{
+	create_flagged_args(owner_lock_hash, cost, 0, token_sale_core::FLAG_PRICE_DENOMINATOR, &price_denominator.to_le_bytes()).into()
+}
+
+#[test]
+fn test_buy_price_denominator()
+{
+	// Buy 10 tokens at a cost of 1 per 2 tokens.
+	let args = create_price_denominator_args(&[0u8; 32], 1, 2);
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(895)
+		.sale_args_out(1_005, 90, args)
+		.sudt_out(100, 10)
+		.expect_pass();
+
+	// Buy 6 tokens at a cost of 100 per 3 tokens.
+	let args = create_price_denominator_args(&[0u8; 32], 100, 3);
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(800)
+		.sale_args_out(1_200, 94, args)
+		.sudt_out(100, 6)
+		.expect_pass();
+
+	// The buy-back cost uses the same denominator.
+	let fields = [90u64.to_le_bytes(), 3u64.to_le_bytes()].concat();
+	let args: Bytes = create_flagged_args(&[0u8; 32], 100, 0, token_sale_core::FLAG_BUY_BACK | token_sale_core::FLAG_PRICE_DENOMINATOR, &fields).into();
+	Scenario::new()
+		.sudt_in(100, 6)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_out(820, 106, args)
+		.capacity_out(280)
+		.expect_pass();
+}
+
+#[test]
+fn test_buy_price_denominator_invalid()
+{
+	// 7 tokens at a cost of 100 per 3 tokens cannot be paid in whole Shannons.
+	let args = create_price_denominator_args(&[0u8; 32], 100, 3);
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(767)
+		.sale_args_out(1_233, 93, args.clone())
+		.sudt_out(100, 7)
+		.expect_err(Error::ExchangeRate);
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(766)
+		.sale_args_out(1_234, 93, args)
+		.sudt_out(100, 7)
+		.expect_err(Error::ExchangeRate);
+
+	// A price denominator of 0.
+	let args = create_price_denominator_args(&[0u8; 32], 100, 0);
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(900)
+		.sale_args_out(1_100, 99, args)
+		.sudt_out(100, 1)
+		.expect_err(Error::InvalidCost);
+}
+
+#[test]
+fn test_invalid_flags()
+{
+	let args: Bytes = create_flagged_args(&[0u8; 32], 100, 0, 0x80, &[]).into();
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(900)
+		.sale_args_out(1_100, 99, args)
+		.sudt_out(100, 1)
+		.expect_err(Error::InvalidFlags);
+
+	let args: Bytes = create_flagged_args(&[0u8; 32], 100, 0, token_sale_core::FLAG_SALE_START, &[0u8; 4]).into();
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(900)
+		.sale_args_out(1_100, 99, args)
+		.sudt_out(100, 1)
+		.expect_err(Error::ArgsLen);
+
+	// The owner can reclaim a Token Sale Cell with unknown flags.
+	let args: Bytes = create_flagged_args(&create_scenario_owner_lock_hash(), 100, 0, 0x80, &[]).into();
+	Scenario::new()
+		.capacity_in(100)
+		.sale_args_in(1_000, 100, args)
+		.capacity_out(1_000)
+		.sudt_out(100, 100)
+		.expect_pass();
+}
+
+/// Create the Token Sale Lock args of a Token Sale Cell with a cost of 100 which can be used alongside others.
+fn create_multiple_cells_args(owner_lock_hash: &[u8; 32]) -> Bytes
+{
+	create_flagged_args(owner_lock_hash, 100, 0, token_sale_core::FLAG_MULTIPLE_CELLS, &[]).into()
+}
+
+#[test]
+fn test_buy_multiple_sale_cells()
+{
+	let args = create_multiple_cells_args(&[0u8; 32]);
+
+	// Buy 3 tokens across two Token Sale Cells, which are validated in aggregate.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_in(1_000, 50, args.clone())
+		.capacity_out(700)
+		.sale_args_out(1_100, 98, args.clone())
+		.sale_args_out(1_200, 49, args.clone())
+		.sudt_out(100, 3)
+		.expect_pass();
+
+	// Tokens can move between the Token Sale Cells as long as the totals balance.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_in(1_000, 50, args.clone())
+		.capacity_out(900)
+		.sale_args_out(1_000, 40, args.clone())
+		.sale_args_out(1_100, 109, args)
+		.sudt_out(100, 1)
+		.expect_pass();
+}
+
+#[test]
+fn test_buy_multiple_sale_cells_invalid()
+{
+	let args = create_multiple_cells_args(&[0u8; 32]);
+
+	// Underpay in aggregate.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_in(1_000, 50, args.clone())
+		.capacity_out(800)
+		.sale_args_out(1_100, 98, args.clone())
+		.sale_args_out(1_100, 49, args.clone())
+		.sudt_out(100, 3)
+		.expect_err(Error::ExchangeRate);
+
+	// Merge two Token Sale Cells into one.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_in(1_000, 50, args.clone())
+		.capacity_out(900)
+		.sale_args_out(2_100, 149, args.clone())
+		.sudt_out(100, 1)
+		.expect_err(Error::InvalidStructure);
+
+	// Split one Token Sale Cell into two.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(900)
+		.sale_args_out(500, 50, args.clone())
+		.sale_args_out(600, 49, args)
+		.sudt_out(100, 1)
+		.expect_err(Error::InvalidStructure);
+
+	// Multiple Token Sale Cells are rejected unless enabled.
+	let args: Bytes = create_flagged_args(&[0u8; 32], 100, 0, 0, &[]).into();
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_in(1_000, 50, args.clone())
+		.capacity_out(700)
+		.sale_args_out(1_100, 98, args.clone())
+		.sale_args_out(1_200, 49, args)
+		.sudt_out(100, 3)
+		.expect_err(Error::InvalidStructure);
+}
+
+#[test]
+fn test_buy_overpayment()
+{
+	let args: Bytes = create_flagged_args(&[0u8; 32], 100, 0, token_sale_core::FLAG_OVERPAYMENT, &[]).into();
+
+	// Overpay by 5 Shannons, which remain in the Token Sale Cell.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(895)
+		.sale_args_out(1_105, 99, args.clone())
+		.sudt_out(100, 1)
+		.expect_pass();
+
+	// Underpayment is still rejected.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(905)
+		.sale_args_out(1_095, 99, args.clone())
+		.sudt_out(100, 1)
+		.expect_err(Error::ExchangeRate);
+
+	// Capacity cannot be donated without buying tokens.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_args_in(1_000, 100, args.clone())
+		.capacity_out(900)
+		.sale_args_out(1_100, 100, args)
+		.expect_err(Error::AmountSudt);
+
+	// Overpayment is rejected unless enabled.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, 100, 0)
+		.capacity_out(895)
+		.sale_out(1_105, 99, 100, 0)
+		.sudt_out(100, 1)
+		.expect_err(Error::ExchangeRate);
+}
+
+#[test]
+fn test_buy_back_overpayment()
+{
+	let fields = 90u64.to_le_bytes();
+	let args: Bytes = create_flagged_args(&[0u8; 32], 100, 0, token_sale_core::FLAG_BUY_BACK | token_sale_core::FLAG_OVERPAYMENT, &fields).into();
+
+	// Withdraw 5 Shannons less than the buy-back cost.
+	Scenario::new()
+		.sudt_in(100, 2)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_out(825, 102, args.clone())
+		.capacity_out(275)
+		.expect_pass();
+
+	// Withdrawing more than the buy-back cost is still rejected.
+	Scenario::new()
+		.sudt_in(100, 2)
+		.sale_args_in(1_000, 100, args.clone())
+		.sale_args_out(815, 102, args)
+		.capacity_out(285)
+		.expect_err(Error::ExchangeRate);
+}
+
+/// Encode bytes as a 0x prefixed hex string.
+fn to_hex(bytes: &[u8]) -> String
+{
+	let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+	format!("0x{}", hex)
+}
+
+/// Rebuild a transaction and its context with deterministic out points, so the transaction serializes identically on every run.
+fn create_deterministic_transaction(context: &Context, tx: &TransactionView) -> (Context, TransactionView)
+{
+	let mut deterministic_context = Context::default();
+	let mut next_out_point = 0u32;
+	let mut move_cell = |deterministic_context: &mut Context, out_point: &OutPoint|
+	{
+		let (cell, data) = context.get_cell(out_point).expect("cell");
+		let mut tx_hash = [0u8; 32];
+		tx_hash[0..4].copy_from_slice(&next_out_point.to_le_bytes());
+		next_out_point += 1;
+		let out_point = OutPoint::new(tx_hash.pack(), 0);
+		deterministic_context.cells_by_data_hash.insert(CellOutput::calc_data_hash(&data), out_point.clone());
+		deterministic_context.create_cell_with_out_point(out_point.clone(), cell, data);
+		out_point
+	};
+
+	let cell_deps: Vec<CellDep> = tx.cell_deps().into_iter().map(|cell_dep| cell_dep.clone().as_builder().out_point(move_cell(&mut deterministic_context, &cell_dep.out_point())).build()).collect();
+	let inputs: Vec<CellInput> = tx.inputs().into_iter().map(|input| input.clone().as_builder().previous_output(move_cell(&mut deterministic_context, &input.previous_output())).build()).collect();
+	let tx = tx.as_advanced_builder().set_cell_deps(cell_deps).set_inputs(inputs).build();
+
+	(deterministic_context, tx)
+}
+
+/// Rebuild a transaction so that every Cell locked by the always success lock is locked by the secp256k1 sighash lock of
+/// the key instead. Owner lock hashes at the start of Lock Script and Type Script args are replaced to match.
+fn create_secp256k1_transaction(context: &Context, resources: &LocalResources, tx: &TransactionView, key: &Privkey) -> (Context, TransactionView)
+{
+	let mut secp256k1_context = Context::default();
+	let always_success_lock = resources.scripts.get("lock-1").unwrap().clone();
+	let always_success_lock_hash = always_success_lock.calc_script_hash();
+
+	// Deploy the secp256k1 sighash lock in place of the always success lock.
+	let sighash_binary = load_system_script("secp256k1_blake160_sighash_all");
+	let secp256k1_lock = Script::new_builder()
+		.code_hash(CellOutput::calc_data_hash(&sighash_binary))
+		.hash_type(ScriptHashType::Data.into())
+		.args(Bytes::from(blake160(&key.pubkey().expect("pubkey").serialize()).to_vec()).pack())
+		.build();
+	let secp256k1_lock_hash = secp256k1_lock.calc_script_hash();
+	let replace_owner = |args: Bytes| -> Bytes
+	{
+		if args.len() >= 32 && args[0..32] == always_success_lock_hash.raw_data()[..] { [&secp256k1_lock_hash.raw_data()[..], &args[32..]].concat().into() } else { args }
+	};
+	let relock = |cell: CellOutput| -> CellOutput
+	{
+		let lock = if cell.lock() == always_success_lock { secp256k1_lock.clone() } else { cell.lock().as_builder().args(replace_owner(cell.lock().args().unpack()).pack()).build() };
+		let type_ = cell.type_().to_opt().map(|type_| type_.clone().as_builder().args(replace_owner(type_.args().unpack()).pack()).build());
+		cell.as_builder().lock(lock).type_(type_.pack()).build()
+	};
+
+	// Copy every Cell dep except the always success lock, and add the secp256k1 sighash lock and its data.
+	let mut cell_deps = vec!();
+	for cell_dep in tx.cell_deps().into_iter()
+	{
+		let (cell, data) = context.get_cell(&cell_dep.out_point()).expect("cell");
+		if data != ALWAYS_SUCCESS.clone()
+		{
+			secp256k1_context.cells_by_data_hash.insert(CellOutput::calc_data_hash(&data), cell_dep.out_point());
+			secp256k1_context.create_cell_with_out_point(cell_dep.out_point(), cell, data);
+			cell_deps.push(cell_dep);
+		}
+	}
+	for binary in [sighash_binary, load_system_script("secp256k1_data")].iter()
+	{
+		cell_deps.push(CellDep::new_builder().out_point(secp256k1_context.deploy_cell(binary.clone())).build());
+	}
+
+	// Relock the inputs and outputs.
+	let inputs: Vec<CellInput> = tx.inputs().into_iter().map(|input|
+	{
+		let (cell, data) = context.get_cell(&input.previous_output()).expect("cell");
+		input.as_builder().previous_output(secp256k1_context.create_cell(relock(cell), data)).build()
+	}).collect();
+	let outputs: Vec<CellOutput> = tx.outputs().into_iter().map(relock).collect();
+	let tx = tx.as_advanced_builder().set_cell_deps(cell_deps).set_inputs(inputs).set_outputs(outputs).build();
+
+	(secp256k1_context, tx)
+}
+
+/// Sign every input locked by the secp256k1 sighash lock of the key, following the sighash all message format.
+/// The inputs are a single script group, so the signature is placed in the witness of the first and covers the rest.
+fn sign_secp256k1_inputs(context: &Context, tx: TransactionView, key: &Privkey) -> TransactionView
+{
+	let lock_args = blake160(&key.pubkey().expect("pubkey").serialize());
+	let group: Vec<usize> = tx.inputs().into_iter().enumerate()
+		.filter(|(_, input)| context.get_cell(&input.previous_output()).expect("cell").0.lock().args().raw_data() == lock_args[..])
+		.map(|(i, _)| i)
+		.collect();
+	let mut witnesses: Vec<Bytes> = tx.witnesses().into_iter().map(|witness| witness.unpack()).collect();
+	witnesses.resize(tx.inputs().len(), Bytes::new());
+
+	// Digest the transaction hash, the first witness with a zeroed signature, and the other witnesses of the group.
+	let witness = WitnessArgs::new_builder().lock(Some(Bytes::from(vec!(0u8; SIGNATURE_LEN))).pack()).build();
+	let mut blake2b = new_blake2b();
+	blake2b.update(&tx.hash().raw_data());
+	blake2b.update(&(witness.as_bytes().len() as u64).to_le_bytes());
+	blake2b.update(&witness.as_bytes());
+	for i in group.iter().skip(1)
+	{
+		blake2b.update(&(witnesses[*i].len() as u64).to_le_bytes());
+		blake2b.update(&witnesses[*i]);
+	}
+	let mut message = [0u8; 32];
+	blake2b.finalize(&mut message);
+
+	// Sign and place the signature in the first witness of the group.
+	let signature = key.sign_recoverable(&H256::from(message)).expect("sign").serialize();
+	witnesses[group[0]] = witness.as_builder().lock(Some(Bytes::from(signature)).pack()).build().as_bytes();
+
+	tx.as_advanced_builder().set_witnesses(witnesses.into_iter().map(|witness| witness.pack()).collect()).build()
+}
+
+/// Serialize a transaction and its resolved Cells as a JSON fixture.
+fn create_transaction_fixture(name: &str, context: &Context, tx: &TransactionView) -> String
+{
+	let inputs: Vec<String> = tx.inputs().into_iter().map(|input|
+	{
+		let (cell, data) = context.get_cell(&input.previous_output()).expect("cell");
+		format!("\t\t{{\"out_point\": \"{}\", \"cell_output\": \"{}\", \"data\": \"{}\"}}", to_hex(input.previous_output().as_slice()), to_hex(cell.as_slice()), to_hex(&data))
+	}).collect();
+	let cell_deps: Vec<String> = tx.cell_deps().into_iter().map(|cell_dep|
+	{
+		let (_, data) = context.get_cell(&cell_dep.out_point()).expect("cell");
+		format!("\t\t{{\"out_point\": \"{}\", \"data_hash\": \"{}\"}}", to_hex(cell_dep.out_point().as_slice()), to_hex(CellOutput::calc_data_hash(&data).as_slice()))
+	}).collect();
+
+	format!("{{\n\t\"name\": \"{}\",\n\t\"tx_hash\": \"{}\",\n\t\"transaction\": \"{}\",\n\t\"inputs\":\n\t[\n{}\n\t],\n\t\"cell_deps\":\n\t[\n{}\n\t]\n}}\n",
+		name, to_hex(tx.hash().as_slice()), to_hex(tx.data().as_slice()), inputs.join(",\n"), cell_deps.join(",\n"))
+}
+
+#[test]
+fn test_golden_transaction_fixtures()
+{
+	// The canonical transactions of a sale.
+	let fixtures = vec!
+	(
+		("buy", Scenario::new()
+			.capacity_in(1_000)
+			.sale_in(1_000, 100, 100, 0)
+			.capacity_out(800)
+			.sale_out(1_100, 99, 100, 0)
+			.sudt_out(100, 1)),
+		("refill", Scenario::new()
+			.token_sale_owner_mode()
+			.sale_in(100, 0, 100, 0)
+			.sudt_in(100, 1_100)
+			.sale_out(100, 1_000, 100, 0)
+			.sudt_out(100, 100)),
+		("reprice", Scenario::new()
+			.token_sale_owner_mode()
+			.sale_in(1_000, 100, 100, 0)
+			.capacity_in(100)
+			.sale_out(1_000, 100, 50, 0)
+			.capacity_out(100)),
+		("close", Scenario::new()
+			.token_sale_owner_mode()
+			.capacity_in(100)
+			.sale_in(1_000, 100, 100, 0)
+			.capacity_out(1_000)
+			.sudt_out(100, 100)),
+	);
+
+	// Fixtures are kept for each test environment, since the code hashes depend on the binaries.
+	let test_env = env::var(TEST_ENV_VAR).unwrap_or_else(|_| "debug".to_owned()).to_lowercase();
+	let fixtures_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(FIXTURES_DIR).join(&test_env);
+	let update = env::var(FIXTURES_UPDATE_ENV_VAR).is_ok();
+
+	// The owner and buyer inputs are signed with a secp256k1 key, so every fixture is a fully signed transaction.
+	let key = Generator::non_crypto_safe_prng(FIXTURES_KEY_SEED).gen_privkey();
+
+	for (name, scenario) in fixtures
+	{
+		// Every fixture must pass verification.
+		let (context, resources, tx) = scenario.build();
+		let (context, tx) = create_secp256k1_transaction(&context, &resources, &tx, &key);
+		let (context, tx) = create_deterministic_transaction(&context, &tx);
+		let tx = sign_secp256k1_inputs(&context, tx, &key);
+		context.verify_tx(&tx, max_cycles()).expect("pass verification");
+
+		// Compare against the recorded fixture, which must exist unless the fixtures are being regenerated.
+		let fixture = create_transaction_fixture(name, &context, &tx);
+		let path = fixtures_dir.join(format!("{}.json", name));
+		if update
+		{
+			fs::create_dir_all(&fixtures_dir).expect("create fixtures directory");
+			fs::write(&path, fixture).expect("write fixture");
+			continue;
+		}
+
+		let recorded = fs::read_to_string(&path).unwrap_or_else(|err| panic!("Unable to read the {} fixture from {}: {}. Run with {}=1 to generate it.", name, path.display(), err, FIXTURES_UPDATE_ENV_VAR));
+		assert!(recorded == fixture, "The {} fixture in {} has changed. Run with {}=1 if this is expected.", name, test_env, FIXTURES_UPDATE_ENV_VAR);
+	}
 }
@@ -0,0 +1,206 @@
+//! Hostile transactions which malleate a valid purchase in an attempt to take tokens or capacity from a Token Sale Cell.
+//!
+//! Every transaction is expected to be rejected by the Token Sale Lock Script with a specific error. The SUDT is in
+//! owner mode wherever the SUDT would otherwise reject the transaction itself.
+
+use super::*;
+
+// Constants
+const COST: u64 = 100; // The token cost of the Token Sale Cell in the purchase Scenario.
+const ID: u32 = 0; // The unique identifier of the Token Sale Cell in the purchase Scenario.
+const SALE_INPUT_INDEX: usize = 1; // The index of the input Token Sale Cell in the purchase Scenario.
+const SALE_OUTPUT_INDEX: usize = 1; // The index of the output Token Sale Cell in the purchase Scenario.
+
+/// Create the Token Sale Lock args of a Token Sale Cell created without owner mode.
+fn create_sale_args(cost: u64, id: u32) -> Vec<u8>
+{
+	let mut args = [0u8; 32].to_vec();
+	args.append(&mut cost.to_le_bytes().to_vec());
+	args.append(&mut id.to_le_bytes().to_vec());
+
+	args
+}
+
+/// Create a Scenario where one token is bought from a Token Sale Cell.
+fn create_buy_scenario() -> Scenario
+{
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, COST, ID)
+		.capacity_out(900)
+		.sale_out(1_100, 99, COST, ID)
+		.sudt_out(100, 1)
+}
+
+#[test]
+fn test_buy_baseline()
+{
+	// The unmodified purchase which every hostile transaction is derived from.
+	create_buy_scenario().expect_pass();
+	create_buy_scenario().sudt_owner_mode().expect_pass();
+}
+
+#[test]
+fn test_spoofed_output_lock_args()
+{
+	let args = create_sale_args(COST, ID);
+
+	// Args which extend, truncate, or repeat the real args serialize to bytes with a matching prefix.
+	let mut extended_args = args.clone();
+	extended_args.push(0);
+	let truncated_args = args[0..token_sale_core::ARGS_LEN].to_vec();
+	let repeated_args = [args.clone(), args.clone()].concat();
+
+	for spoofed_args in [extended_args, truncated_args, repeated_args].iter()
+	{
+		Scenario::new()
+			.capacity_in(1_000)
+			.sale_args_in(1_000, 100, args.clone().into())
+			.capacity_out(900)
+			.sale_args_out(1_100, 99, spoofed_args.clone().into())
+			.sudt_out(100, 1)
+			.expect_err(Error::InvalidStructure);
+	}
+}
+
+#[test]
+fn test_spoofed_output_lock_hash_type()
+{
+	let (mut context, resources, tx) = create_buy_scenario().build();
+	let (output, output_data) = tx.output_with_data(SALE_OUTPUT_INDEX).unwrap();
+
+	// The same code hash and args with a different hash type is a different Lock Script.
+	let spoofed_lock = output.lock().as_builder().hash_type(ScriptHashType::Type.into()).build();
+	let spoofed_output = output.as_builder().lock(spoofed_lock).build();
+	let tx = replace_output(&tx, SALE_OUTPUT_INDEX, spoofed_output, output_data);
+	expect_tx_err(&mut context, &resources, &tx, Error::InvalidStructure);
+}
+
+#[test]
+fn test_spoofed_output_type_args()
+{
+	let (mut context, resources, tx) = create_buy_scenario().sudt_owner_mode().build();
+	let (output, output_data) = tx.output_with_data(SALE_OUTPUT_INDEX).unwrap();
+	let type_script = output.type_().to_opt().unwrap();
+
+	// SUDT args which extend the real args belong to a different token.
+	let mut spoofed_args: Vec<u8> = type_script.args().unpack();
+	spoofed_args.push(0);
+	let spoofed_type = type_script.as_builder().args(Bytes::from(spoofed_args).pack()).build();
+	let spoofed_output = output.clone().as_builder().type_(Some(spoofed_type.clone()).pack()).build();
+	let tx = replace_output(&tx, SALE_OUTPUT_INDEX, spoofed_output, output_data.clone());
+
+	// The spoofed tokens are supplied by an input, so only the Token Sale Lock Script rejects the transaction.
+	let spoofed_input = output.as_builder().lock(resources.scripts.get("lock-1").unwrap().clone()).type_(Some(spoofed_type).pack()).build();
+	let spoofed_input = CellInput::new_builder().previous_output(context.create_cell(spoofed_input, output_data)).build();
+	let tx = tx.as_advanced_builder().input(spoofed_input).build();
+	expect_tx_err(&mut context, &resources, &tx, Error::InvalidStructure);
+}
+
+#[test]
+fn test_extra_output_reusing_sale_lock()
+{
+	let (mut context, resources, tx) = create_buy_scenario().sudt_owner_mode().build();
+	let (output, _output_data) = tx.output_with_data(SALE_OUTPUT_INDEX).unwrap();
+	let type_script = output.type_().to_opt().unwrap();
+	let other_type = type_script.clone().as_builder().args(Bytes::from([0xFFu8; 32].to_vec()).pack()).build();
+	let capacity_type = resources.scripts.get("lock-1").unwrap().clone();
+
+	// Extra outputs using the Token Sale Lock with no Type Script, a different Type Script, or the same Type Script.
+	let extra_outputs = vec!
+	(
+		(output.clone().as_builder().capacity(100u64.pack()).type_(None::<Script>.pack()).build(), Bytes::new()),
+		(output.clone().as_builder().capacity(100u64.pack()).type_(Some(other_type).pack()).build(), 0u128.to_le_bytes().to_vec().into()),
+		(output.clone().as_builder().capacity(100u64.pack()).type_(Some(capacity_type).pack()).build(), Bytes::new()),
+		(output.clone().as_builder().capacity(100u64.pack()).build(), 0u128.to_le_bytes().to_vec().into()),
+	);
+
+	for (extra_output, extra_output_data) in extra_outputs
+	{
+		let tx = add_output(&tx, extra_output, extra_output_data);
+		expect_tx_err(&mut context, &resources, &tx, Error::InvalidStructure);
+	}
+}
+
+#[test]
+fn test_duplicated_sale_args()
+{
+	// Two input Token Sale Cells with identical args form a single script group, even when each is balanced.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, COST, ID)
+		.sale_in(1_000, 100, COST, ID)
+		.capacity_out(800)
+		.sale_out(1_100, 99, COST, ID)
+		.sale_out(1_100, 99, COST, ID)
+		.sudt_out(100, 2)
+		.expect_err(Error::InvalidStructure);
+
+	// A Token Sale Cell split into two outputs with identical args whose totals balance.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, COST, ID)
+		.capacity_out(900)
+		.sale_out(1_050, 50, COST, ID)
+		.sale_out(50, 49, COST, ID)
+		.sudt_out(100, 1)
+		.expect_err(Error::InvalidStructure);
+
+	// Two script groups where the output of one duplicates the args of the other.
+	Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, COST, ID)
+		.sale_in(1_000, 100, COST, ID + 1)
+		.capacity_out(800)
+		.sale_out(1_100, 99, COST, ID)
+		.sale_out(1_100, 99, COST, ID)
+		.sudt_out(100, 2)
+		.expect_err(Error::InvalidStructure);
+}
+
+#[test]
+fn test_oversized_data()
+{
+	// Data appended to the output Token Sale Cell.
+	for extension in [vec!(0u8), vec!(0xAAu8; 4_096)].iter()
+	{
+		let (mut context, resources, tx) = create_buy_scenario().sudt_owner_mode().build();
+		let (output, _output_data) = tx.output_with_data(SALE_OUTPUT_INDEX).unwrap();
+		let tx = replace_output(&tx, SALE_OUTPUT_INDEX, output, create_xudt_data(99, extension));
+		expect_tx_err(&mut context, &resources, &tx, Error::InvalidStructure);
+	}
+
+	// Data following the token amount of the input Token Sale Cell which is altered or removed.
+	for output_extension in [vec!(1u8, 2, 4), vec!(1u8, 2), vec!()].iter()
+	{
+		let (mut context, resources, tx) = create_buy_scenario().sudt_owner_mode().build();
+		let (output, _output_data) = tx.output_with_data(SALE_OUTPUT_INDEX).unwrap();
+		let input_out_point = tx.inputs().get(SALE_INPUT_INDEX).unwrap().previous_output();
+		let (input, _input_data) = context.get_cell(&input_out_point).unwrap();
+		let tx = replace_input(&mut context, &tx, SALE_INPUT_INDEX, input.clone(), create_xudt_data(100, &[1, 2, 3]));
+		let valid_tx = replace_output(&tx, SALE_OUTPUT_INDEX, output.clone(), create_xudt_data(99, &[1, 2, 3]));
+		context.verify_tx(&valid_tx, max_cycles()).expect("pass verification");
+		let tx = replace_output(&tx, SALE_OUTPUT_INDEX, output, create_xudt_data(99, output_extension));
+		expect_tx_err(&mut context, &resources, &tx, Error::InvalidStructure);
+	}
+
+	// Data too short to contain the token amount.
+	let (mut context, resources, tx) = create_buy_scenario().sudt_owner_mode().build();
+	let (output, _output_data) = tx.output_with_data(SALE_OUTPUT_INDEX).unwrap();
+	let tx = replace_output(&tx, SALE_OUTPUT_INDEX, output, 99u64.to_le_bytes().to_vec().into());
+	expect_tx_err(&mut context, &resources, &tx, Error::Encoding);
+}
+
+#[test]
+fn test_unrelated_oversized_cells()
+{
+	// Unrelated Cells with data or args larger than the syscall buffer must not prevent a valid purchase.
+	let (mut context, resources, tx) = create_buy_scenario().build();
+	let lock_script = resources.scripts.get("lock-1").unwrap().clone();
+	let large_lock = lock_script.clone().as_builder().args(Bytes::from(vec!(0xAAu8; 4_096)).pack()).build();
+	let (input, _input_data) = context.get_cell(&tx.inputs().get(0).unwrap().previous_output()).unwrap();
+	let tx = replace_input(&mut context, &tx, 0, input, vec!(0xAAu8; 4_096).into());
+	let tx = add_output(&tx, CellOutput::new_builder().lock(lock_script).build(), vec!(0xAAu8; 4_096).into());
+	let tx = add_output(&tx, CellOutput::new_builder().lock(large_lock).build(), Bytes::new());
+	context.verify_tx(&tx, max_cycles()).expect("pass verification");
+}
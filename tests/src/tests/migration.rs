@@ -0,0 +1,145 @@
+//! Migration of a live Token Sale Cell from one version of the Token Sale Lock Script to another.
+//!
+//! The owner consumes the Token Sale Cell in owner mode and recreates it under the new code hash with identical args
+//! and data. Owner mode unlocks unconditionally, so preserving the inventory, cost, and identifier is the
+//! responsibility of the owner's tooling, which can check it with `validate_migration()`.
+
+use super::*;
+
+// Constants
+const COST: u64 = 100; // The token cost of the migrated Token Sale Cell.
+const ID: u32 = 0; // The unique identifier of the migrated Token Sale Cell.
+const SALE_INPUT_INDEX: usize = 1; // The index of the input Token Sale Cell in each Scenario.
+const SALE_OUTPUT_INDEX: usize = 1; // The index of the output Token Sale Cell in each Scenario.
+const VERSION_PADDING: usize = 8; // The number of bytes appended to the binary to create a new version.
+
+/// Deploy a new version of the Token Sale Lock Script and return its Lock Script with the specified args.
+/// Padding the binary changes its data hash, standing in for a rebuilt binary.
+fn deploy_new_version(context: &mut Context, resources: &LocalResources, args: Bytes) -> (Script, CellDep)
+{
+	let mut binary = resources.binaries.get("token-sale").unwrap().to_vec();
+	binary.resize(binary.len() + VERSION_PADDING, 0);
+	let binary: Bytes = binary.into();
+
+	let out_point = context.deploy_cell(binary.clone());
+	let lock_script = Script::new_builder()
+		.code_hash(CellOutput::calc_data_hash(&binary))
+		.hash_type(ScriptHashType::Data.into())
+		.args(args.pack())
+		.build();
+	let cell_dep = CellDep::new_builder().out_point(out_point).build();
+
+	(lock_script, cell_dep)
+}
+
+/// Create a transaction where the Token Sale Cell is recreated with identical args and data under a new version.
+fn create_migration_transaction(scenario: Scenario) -> (Context, LocalResources, TransactionView)
+{
+	let (mut context, resources, tx) = scenario
+		.capacity_in(100)
+		.sale_in(1_000, 100, COST, ID)
+		.capacity_out(100)
+		.sale_out(1_000, 100, COST, ID)
+		.build();
+
+	// Move the output Token Sale Cell to the new version.
+	let (output, output_data) = tx.output_with_data(SALE_OUTPUT_INDEX).unwrap();
+	let (new_lock_script, new_lock_dep) = deploy_new_version(&mut context, &resources, output.lock().args().raw_data());
+	let output = output.as_builder().lock(new_lock_script).build();
+	let tx = replace_output(&tx, SALE_OUTPUT_INDEX, output, output_data);
+	let tx = tx.as_advanced_builder().cell_dep(new_lock_dep).build();
+
+	(context, resources, tx)
+}
+
+/// Create a transaction where one token is bought from a Token Sale Cell which has been migrated to a new version.
+/// The output Token Sale Cell receives the specified capacity.
+fn create_migrated_buy_transaction(sale_capacity_out: u64) -> (Context, TransactionView)
+{
+	let (mut context, resources, tx) = Scenario::new()
+		.capacity_in(1_000)
+		.sale_in(1_000, 100, COST, ID)
+		.capacity_out(2_000 - sale_capacity_out)
+		.sale_out(sale_capacity_out, 99, COST, ID)
+		.sudt_out(100, 1)
+		.build();
+
+	// Move the input and output Token Sale Cells to the new version.
+	let (output, output_data) = tx.output_with_data(SALE_OUTPUT_INDEX).unwrap();
+	let (new_lock_script, new_lock_dep) = deploy_new_version(&mut context, &resources, output.lock().args().raw_data());
+	let (input, input_data) = context.get_cell(&tx.inputs().get(SALE_INPUT_INDEX).unwrap().previous_output()).unwrap();
+	let input = input.as_builder().lock(new_lock_script.clone()).build();
+	let tx = replace_input(&mut context, &tx, SALE_INPUT_INDEX, input, input_data);
+	let output = output.as_builder().lock(new_lock_script).build();
+	let tx = replace_output(&tx, SALE_OUTPUT_INDEX, output, output_data);
+	let tx = tx.as_advanced_builder().cell_dep(new_lock_dep).build();
+
+	(context, tx)
+}
+
+#[test]
+fn test_migrate_sale_cell()
+{
+	let (context, resources, tx) = create_migration_transaction(Scenario::new().token_sale_owner_mode());
+	assert_eq!(validate_transaction_host(&context, &resources, &tx), Ok(()));
+	context.verify_tx(&tx, max_cycles()).expect("pass verification");
+}
+
+#[test]
+fn test_migrate_sale_cell_no_owner()
+{
+	// Without owner mode the old version finds no output Token Sale Cell.
+	let (mut context, resources, tx) = create_migration_transaction(Scenario::new());
+	expect_tx_err(&mut context, &resources, &tx, Error::InvalidStructure);
+}
+
+#[test]
+fn test_buy_from_migrated_sale_cell()
+{
+	// The new version enforces the same exchange rate.
+	let (context, tx) = create_migrated_buy_transaction(1_100);
+	context.verify_tx(&tx, max_cycles()).expect("pass verification");
+
+	let (context, tx) = create_migrated_buy_transaction(1_050);
+	let err = context.verify_tx(&tx, max_cycles()).unwrap_err();
+	assert_error_eq!(err, ScriptError::ValidationFailure(Error::ExchangeRate as i8));
+}
+
+#[test]
+fn test_core_validate_migration()
+{
+	use token_sale_core::validate_migration;
+
+	// Constants
+	const NEW_CODE_HASH: [u8; 32] = [5u8; 32]; // The code hash of the new version.
+
+	let data_hash_type: u8 = ScriptHashType::Data.into();
+	let args = create_core_args(&CORE_OWNER_LOCK_HASH, COST);
+	let other_args = create_core_args(&CORE_OWNER_LOCK_HASH, COST + 1);
+	let sudt = create_core_script(&CORE_SUDT_CODE_HASH, &CORE_OWNER_LOCK_HASH);
+	let other_sudt = create_core_script(&CORE_SUDT_CODE_HASH, &CORE_OTHER_LOCK_HASH);
+	let tokens = 100u128.to_le_bytes();
+	let other_tokens = 99u128.to_le_bytes();
+	let old_cell = |args| create_core_cell(1_000, create_core_script(&CORE_TOKEN_SALE_CODE_HASH, args), &CORE_OTHER_LOCK_HASH, Some(sudt), &tokens);
+	let new_cell = |args, type_, data| create_core_cell(1_000, create_core_script(&NEW_CODE_HASH, args), &CORE_OTHER_LOCK_HASH, Some(type_), data);
+	let validate = |inputs: &[_], outputs: &[_]| validate_migration(&CORE_TOKEN_SALE_CODE_HASH, data_hash_type, &NEW_CODE_HASH, data_hash_type, inputs, outputs);
+
+	// The Token Sale Cell is recreated with identical args, Type Script, and data.
+	assert_eq!(validate(&[old_cell(&args)], &[new_cell(&args, sudt, &tokens)]), Ok(()));
+	assert_eq!(validate(&[old_cell(&args), old_cell(&other_args)], &[new_cell(&args, sudt, &tokens), new_cell(&other_args, sudt, &tokens)]), Ok(()));
+
+	// The args, Type Script, and data must be preserved.
+	assert_eq!(validate(&[old_cell(&args)], &[new_cell(&other_args, sudt, &tokens)]), Err(Error::InvalidStructure));
+	assert_eq!(validate(&[old_cell(&args)], &[new_cell(&args, other_sudt, &tokens)]), Err(Error::InvalidStructure));
+	assert_eq!(validate(&[old_cell(&args)], &[new_cell(&args, sudt, &other_tokens)]), Err(Error::InvalidStructure));
+
+	// Every Token Sale Cell must be recreated exactly once, and the Cells are paired in order.
+	assert_eq!(validate(&[old_cell(&args)], &[]), Err(Error::InvalidStructure));
+	assert_eq!(validate(&[], &[new_cell(&args, sudt, &tokens)]), Err(Error::InvalidStructure));
+	assert_eq!(validate(&[], &[]), Err(Error::InvalidStructure));
+	assert_eq!(validate(&[old_cell(&args)], &[new_cell(&args, sudt, &tokens), new_cell(&args, sudt, &tokens)]), Err(Error::InvalidStructure));
+	assert_eq!(validate(&[old_cell(&args), old_cell(&other_args)], &[new_cell(&other_args, sudt, &tokens), new_cell(&args, sudt, &tokens)]), Err(Error::InvalidStructure));
+
+	// A Cell which stays on the old version is not migrated.
+	assert_eq!(validate(&[old_cell(&args)], &[old_cell(&args)]), Err(Error::InvalidStructure));
+}
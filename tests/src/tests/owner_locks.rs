@@ -0,0 +1,228 @@
+//! Owner mode with the system locks which owners use in practice, in place of ALWAYS_SUCCESS.
+//!
+//! Owner mode is enabled by the lock hash of an owner input, which covers the code hash, hash type, and args of its
+//! Lock Script. Each owner lock is deployed so that it can be referenced by both data hash and type hash, and a
+//! Token Sale Cell which records the lock hash of one must not be unlocked by the other.
+
+use super::*;
+use ckb_tool::ckb_crypto::secp::Generator;
+
+// Constants
+const COST: u64 = 100; // The token cost of the Token Sale Cell.
+const ID: u32 = 0; // The unique identifier of the Token Sale Cell.
+const OWNER_CAPACITY: u64 = 1_000; // The capacity of the owner input Cell.
+const OWNER_KEYS_SEED: u64 = 1; // The seed used to generate the owner keys.
+const OTHER_KEYS_SEED: u64 = 2; // The seed used to generate keys which do not belong to the owner.
+const MULTISIG_THRESHOLD: u8 = 2; // The number of signatures required by the multisig owner lock.
+const MULTISIG_KEYS: u8 = 3; // The number of keys in the multisig owner lock.
+
+/// A system lock used by the owner of a Token Sale Cell.
+#[derive(Clone, Copy, Debug)]
+enum OwnerLock
+{
+	Secp256k1Sighash,
+	Secp256k1Multisig,
+	AnyoneCanPay,
+}
+
+/// The lock args of an owner lock and the keys which sign for it.
+struct OwnerKeys
+{
+	args: Bytes,
+	witness_lock_prefix: Vec<u8>,
+	keys: Vec<Privkey>,
+}
+
+/// Load the binary of an owner lock. ACP must be placed in the deps directory.
+fn load_owner_lock_binary(owner_lock: OwnerLock) -> Bytes
+{
+	match owner_lock
+	{
+		OwnerLock::Secp256k1Sighash => load_system_script("secp256k1_blake160_sighash_all"),
+		OwnerLock::Secp256k1Multisig => load_system_script("secp256k1_blake160_multisig_all"),
+		OwnerLock::AnyoneCanPay => load_deps_binary(ANYONE_CAN_PAY_FILE),
+	}
+}
+
+/// Create the lock args and signing keys of an owner lock from a seed.
+fn create_owner_keys(owner_lock: OwnerLock, seed: u64) -> OwnerKeys
+{
+	let mut generator = Generator::non_crypto_safe_prng(seed);
+	match owner_lock
+	{
+		OwnerLock::Secp256k1Sighash | OwnerLock::AnyoneCanPay =>
+		{
+			let key = generator.gen_privkey();
+			let args = blake160(&key.pubkey().expect("pubkey").serialize());
+			OwnerKeys { args: args.to_vec().into(), witness_lock_prefix: vec!(), keys: vec!(key) }
+		},
+		OwnerLock::Secp256k1Multisig =>
+		{
+			// The multisig script is: reserved, require first n, threshold, key count, then the key hashes.
+			let keys: Vec<Privkey> = (0..MULTISIG_KEYS).map(|_| generator.gen_privkey()).collect();
+			let mut multisig_script = vec!(0u8, 0u8, MULTISIG_THRESHOLD, MULTISIG_KEYS);
+			for key in keys.iter()
+			{
+				multisig_script.extend_from_slice(&blake160(&key.pubkey().expect("pubkey").serialize()));
+			}
+			let args = blake160(&multisig_script);
+			OwnerKeys { args: args.to_vec().into(), witness_lock_prefix: multisig_script, keys: keys.into_iter().take(MULTISIG_THRESHOLD as usize).collect() }
+		},
+	}
+}
+
+/// Create the Type Script of the Cell which an owner lock is deployed to, standing in for a Type ID.
+fn create_owner_lock_type_script(binary: &Bytes) -> Script
+{
+	Script::new_builder()
+		.code_hash(CellOutput::calc_data_hash(&ALWAYS_SUCCESS))
+		.hash_type(ScriptHashType::Data.into())
+		.args(CellOutput::calc_data_hash(binary).as_bytes().pack())
+		.build()
+}
+
+/// Create an owner Lock Script which references the binary using the specified hash type.
+fn create_owner_lock_script(binary: &Bytes, hash_type: ScriptHashType, args: Bytes) -> Script
+{
+	let code_hash = match hash_type
+	{
+		ScriptHashType::Data => CellOutput::calc_data_hash(binary),
+		ScriptHashType::Type => create_owner_lock_type_script(binary).calc_script_hash(),
+	};
+
+	Script::new_builder()
+		.code_hash(code_hash)
+		.hash_type(hash_type.into())
+		.args(args.pack())
+		.build()
+}
+
+/// Deploy an owner lock so that it can be referenced using the specified hash type.
+fn deploy_owner_lock(context: &mut Context, binary: &Bytes, hash_type: ScriptHashType) -> CellDep
+{
+	let out_point = match hash_type
+	{
+		ScriptHashType::Data => context.deploy_cell(binary.clone()),
+		ScriptHashType::Type =>
+		{
+			let cell = CellOutput::new_builder()
+				.capacity(Capacity::bytes(binary.len()).expect("capacity").as_u64().pack())
+				.type_(Some(create_owner_lock_type_script(binary)).pack())
+				.build();
+			context.create_cell(cell, binary.clone())
+		},
+	};
+
+	CellDep::new_builder().out_point(out_point).build()
+}
+
+/// Sign the input at the specified index with the owner keys, following the secp256k1 sighash all message format.
+/// The witness lock is the prefix followed by one signature for each key.
+fn sign_owner_input(tx: TransactionView, index: usize, owner: &OwnerKeys) -> TransactionView
+{
+	// Digest the transaction hash and the witness with zeroed signatures.
+	let mut zero_lock = owner.witness_lock_prefix.clone();
+	zero_lock.resize(zero_lock.len() + SIGNATURE_LEN * owner.keys.len(), 0);
+	let witness = WitnessArgs::new_builder().lock(Some(Bytes::from(zero_lock)).pack()).build();
+	let mut blake2b = new_blake2b();
+	blake2b.update(&tx.hash().raw_data());
+	blake2b.update(&(witness.as_bytes().len() as u64).to_le_bytes());
+	blake2b.update(&witness.as_bytes());
+	let mut message = [0u8; 32];
+	blake2b.finalize(&mut message);
+	let message = H256::from(message);
+
+	// Sign with each key.
+	let mut lock = owner.witness_lock_prefix.clone();
+	for key in owner.keys.iter()
+	{
+		lock.extend_from_slice(&key.sign_recoverable(&message).expect("sign").serialize());
+	}
+	let witness = witness.as_builder().lock(Some(Bytes::from(lock)).pack()).build();
+
+	// Every other input has an empty witness.
+	let mut witnesses = vec!(Bytes::new().pack(); tx.inputs().len());
+	witnesses[index] = witness.as_bytes().pack();
+
+	tx.as_advanced_builder().set_witnesses(witnesses).build()
+}
+
+/// Create a transaction where the owner withdraws half of the capacity and tokens from a Token Sale Cell.
+/// The owner input uses the owner lock with the specified hash type. The Token Sale Lock args record the lock hash
+/// of the specified args lock.
+fn create_owner_transaction(binary: &Bytes, hash_type: ScriptHashType, owner: &OwnerKeys, args_lock: &Script) -> (Context, LocalResources, TransactionView)
+{
+	// Prepare the Token Sale Lock args.
+	let args_lock_hash: [u8; 32] = args_lock.calc_script_hash().unpack();
+	let mut args = create_core_args(&args_lock_hash, COST);
+	args.append(&mut ID.to_le_bytes().to_vec());
+
+	// The withdrawal is only valid in owner mode.
+	let (mut context, resources, tx) = Scenario::new()
+		.sale_args_in(10_000, 100, args.clone().into())
+		.sale_args_out(5_000, 50, args.into())
+		.sudt_out(5_000, 50)
+		.build();
+
+	// Prepare the owner input and its cell deps.
+	let lock_dep = deploy_owner_lock(&mut context, binary, hash_type);
+	let secp256k1_data_out_point = context.deploy_cell(load_system_script("secp256k1_data"));
+	let secp256k1_data_dep = CellDep::new_builder().out_point(secp256k1_data_out_point).build();
+	let owner_lock = create_owner_lock_script(binary, hash_type, owner.args.clone());
+	let owner_cell = CellOutput::new_builder().capacity(OWNER_CAPACITY.pack()).lock(owner_lock).build();
+	let owner_input = CellInput::new_builder().previous_output(context.create_cell(owner_cell, Bytes::new())).build();
+
+	// Add the owner input and sign it.
+	let index = tx.inputs().len();
+	let tx = tx.as_advanced_builder().input(owner_input).cell_dep(lock_dep).cell_dep(secp256k1_data_dep).build();
+	let tx = sign_owner_input(tx, index, owner);
+
+	(context, resources, tx)
+}
+
+/// Verify that an owner lock enables owner mode only when referenced by the lock hash recorded in the args, using
+/// both hash types.
+fn check_owner_lock(owner_lock: OwnerLock)
+{
+	let binary = load_owner_lock_binary(owner_lock);
+	let owner = create_owner_keys(owner_lock, OWNER_KEYS_SEED);
+	let other = create_owner_keys(owner_lock, OTHER_KEYS_SEED);
+
+	for (hash_type, other_hash_type) in [(ScriptHashType::Data, ScriptHashType::Type), (ScriptHashType::Type, ScriptHashType::Data)].iter()
+	{
+		let hash_type_value: u8 = (*hash_type).into();
+		println!("Owner Lock: {:?} Hash Type: {}", owner_lock, hash_type_value);
+		let owner_lock_script = create_owner_lock_script(&binary, *hash_type, owner.args.clone());
+
+		// The owner lock hash enables owner mode.
+		let (context, resources, tx) = create_owner_transaction(&binary, *hash_type, &owner, &owner_lock_script);
+		assert_eq!(validate_transaction_host(&context, &resources, &tx), Ok(()));
+		context.verify_tx(&tx, max_cycles()).expect("pass verification");
+
+		// The same code and args referenced using the other hash type have a different lock hash.
+		let other_hash_type_script = create_owner_lock_script(&binary, *other_hash_type, owner.args.clone());
+		let (mut context, resources, tx) = create_owner_transaction(&binary, *hash_type, &owner, &other_hash_type_script);
+		expect_tx_err(&mut context, &resources, &tx, Error::AmountCkbytes);
+
+		// A lock owned by different keys has a different lock hash.
+		let other_keys_script = create_owner_lock_script(&binary, *hash_type, other.args.clone());
+		let (mut context, resources, tx) = create_owner_transaction(&binary, *hash_type, &owner, &other_keys_script);
+		expect_tx_err(&mut context, &resources, &tx, Error::AmountCkbytes);
+	}
+}
+
+#[test]
+fn test_owner_lock_matrix()
+{
+	for owner_lock in [OwnerLock::Secp256k1Sighash, OwnerLock::Secp256k1Multisig].iter()
+	{
+		check_owner_lock(*owner_lock);
+	}
+}
+
+#[test]
+#[ignore]
+fn test_anyone_can_pay_owner_lock()
+{
+	check_owner_lock(OwnerLock::AnyoneCanPay);
+}